@@ -1,5 +1,4 @@
 // imports
-use failure;
 use std::{
     io::Read,
     net::{TcpListener, TcpStream},
@@ -8,26 +7,45 @@ use std::{
 use tcp_proxy::http_utils::{
     connection::write_to_stream,
     constants::*,
-    errors,
     formatting::{get_origin_addr, Result},
     response::{write_error_res, Payload},
 };
 
 /// Get the payload from the endpoint
 /// convert response to http response
+///
+/// Validators from the proxy (`if-none-match`, `if-modified-since`) are passed to the endpoint,
+/// a `304 Not Modified` is returned without a body
 /// TODO: url must be validated
 /// TODO: url must be supported by already-implemented structs
-fn call_api(url: String) -> Result<http::Response<Vec<u8>>> {
-    let res = reqwest::blocking::get(&url)?;
+fn call_api(url: String, req_headers: &http::HeaderMap) -> Result<http::Response<Vec<u8>>> {
+    let mut api_req = reqwest::blocking::Client::new().get(&url);
+    for header_name in ["if-none-match", "if-modified-since"] {
+        if let Some(header_value) = req_headers.get(header_name) {
+            api_req = api_req.header(header_name, header_value);
+        }
+    }
+    let res = api_req.send()?;
 
     // return to `http` lib response
     let mut new_res = http::Response::builder()
-        .status(&res.status())
+        .status(res.status())
         .version(http::Version::HTTP_11);
     for (header_name, header_value) in res.headers() {
         new_res = new_res.header(header_name, header_value);
     }
 
+    // not modified: nothing to validate, the proxy keeps its stored body
+    if res.status() == reqwest::StatusCode::NOT_MODIFIED {
+        return Ok(new_res.body(Vec::new()).unwrap());
+    }
+
+    // errors (i.e. 404 for a missing block) are passed on as is, the proxy decides whether to cache them
+    if !res.status().is_success() {
+        let res_body = res.bytes()?.to_vec();
+        return Ok(new_res.body(res_body).unwrap());
+    }
+
     let res_body = res.text()?;
 
     // validate
@@ -138,7 +156,7 @@ fn main() {
             // call external api, get json response; build response body
 
             // TODO: propagate error + code to http response
-            let res_with_json = match call_api(url) {
+            let res_with_json = match call_api(url, new_req.headers()) {
                 Ok(json) => json,
                 Err(err) => {
                     eprintln!("Error calling api or json: {}", failure::err_msg(err));
@@ -1,29 +1,44 @@
 // libs
 use std::{
-    net::TcpListener,
+    net::{TcpListener, TcpStream},
     process::exit,
     sync::Arc,
     thread::{self, JoinHandle},
 };
 // local
 use tcp_proxy::{
-    cache_utils::{cache::HTTPCache, ttl::purge_expired_cache_entries},
+    cache_utils::{
+        cache::HTTPCache,
+        config::CacheConfig,
+        snapshot::{import_snapshot, spawn_snapshot_signal_handler},
+        ttl::spawn_expiry_sweeper,
+        warm::{read_warm_urls, warm_cache},
+    },
     http_utils::{
-        connection::handle_client_proxy_connection,
-        formatting::{get_proxy_addr, Result},
+        connection::handle_client_proxy_connection, formatting::get_proxy_addr,
+        response::write_error_res,
     },
 };
 
-/// TODO: incomplete. Handle all error cases with appropriate error messages
-/// And HTTP response status
-fn write_error_response_to_client(e: failure::Error) -> Result<()> {
-    eprintln!("WRITING ERROR RESPONSE TO CLIENT: \n_______\n{e}\n_______\n");
-
-    Ok(())
+/// Log an error that ended a client connection, and send it to the client as a `502 Bad Gateway`
+///
+/// TODO: map request errors (malformed, too large) to a `4xx` status
+fn write_error_response_to_client(stream: &mut TcpStream, e: failure::Error) {
+    eprintln!("error handling client connection: {e}");
+    write_error_res(&e, stream, 502);
 }
 
 fn main() {
-    let proxy_listener = match TcpListener::bind(&get_proxy_addr()) {
+    // 0.1) read config
+    let config = match CacheConfig::from_args(std::env::args()) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Invalid arguments: {}", e);
+            exit(1);
+        }
+    };
+
+    let proxy_listener = match TcpListener::bind(get_proxy_addr()) {
         Ok(pl) => {
             println!("Running at endpoint: {}", pl.local_addr().unwrap());
 
@@ -35,10 +50,56 @@ fn main() {
         }
     };
 
-    // 0.2) init cache
-    let cache_arc_rw = Arc::from(HTTPCache::new());
+    // 0.2) init cache on the configured backend, reloading the disk tier if configured
+    let cache = match HTTPCache::open(config.clone()) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Unable to open cache backend: {}", e);
+            exit(1);
+        }
+    };
+    if let Some(snapshot_path) = &config.snapshot_load {
+        match import_snapshot(&cache, snapshot_path) {
+            Ok(amt_loaded) => {
+                println!("Loaded {amt_loaded} entries from snapshot {snapshot_path:?}")
+            }
+            Err(e) => eprintln!("Unable to load snapshot {snapshot_path:?}: {}", e),
+        }
+    }
+    let cache_arc_rw = Arc::from(cache);
+    // save a snapshot on demand (SIGUSR1) and on shutdown
+    if let Some(snapshot_path) = &config.snapshot_save {
+        let handler =
+            spawn_snapshot_signal_handler(Arc::clone(&cache_arc_rw), snapshot_path.clone());
+        if let Err(e) = handler {
+            eprintln!("Unable to handle snapshot signals: {}", e);
+            exit(1);
+        }
+    }
+    // remove entries past the ttl, outside of the accept loop
+    spawn_expiry_sweeper(Arc::clone(&cache_arc_rw), config.sweep_interval);
+
+    // 0.3) warm the cache before accepting clients, connections wait in the listener's backlog
+    if let Some(warm_urls_path) = &config.warm_urls {
+        match read_warm_urls(warm_urls_path) {
+            Ok(target_urls) => {
+                let amt_urls = target_urls.len();
+                let report = warm_cache(&cache_arc_rw, target_urls, config.warm_concurrency);
+                println!(
+                    "Warmed cache: {} fetched, {} already cached, {} failed (of {amt_urls})",
+                    report.amt_fetched,
+                    report.amt_skipped,
+                    report.failures.len()
+                );
+                for (target_url, e) in &report.failures {
+                    eprintln!("warming {target_url} failed: {e}");
+                }
+            }
+            Err(e) => eprintln!("Unable to read warm urls {warm_urls_path:?}: {}", e),
+        }
+    }
 
-    // 0.3) init thread pool
+    // 0.4) init thread pool
     let mut thread_handles: Vec<JoinHandle<()>> = Vec::new();
 
     // 1) handle incoming connections
@@ -59,19 +120,20 @@ fn main() {
             let t = chrono::offset::Local::now();
             println!("Thread-req from: {:?} {t}", client_proxy_stream.peer_addr());
 
-            // handle errors during connection
-            match handle_client_proxy_connection(client_proxy_stream, &Arc::clone(&cache)) {
-                Ok(_) => {}
-                Err(e) => {
-                    eprintln!("error handling client connection: {}", e)
+            // handle errors during connection, the handler takes the stream so a copy is kept to answer on
+            let error_stream = client_proxy_stream.try_clone();
+            if let Err(e) = handle_client_proxy_connection(client_proxy_stream, &Arc::clone(&cache))
+            {
+                match error_stream {
+                    Ok(mut error_stream) => write_error_response_to_client(&mut error_stream, e),
+                    Err(stream_err) => {
+                        eprintln!("error handling client connection: {e} ({stream_err})")
+                    }
                 }
-            };
+            }
         });
 
-        // 2) remove entries past the ttl
-        purge_expired_cache_entries(Arc::clone(&cache_arc_rw));
-
-        // 3) push handle
+        // 2) push handle
         thread_handles.push(handle);
 
         println!("\n\nEnd of connection\n");
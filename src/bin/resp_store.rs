@@ -0,0 +1,220 @@
+// imports
+use std::{
+    collections::HashMap,
+    io::{BufReader, BufWriter, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+// local
+use tcp_proxy::{
+    cache_utils::redis::{read_value, write_value, RespValue},
+    http_utils::{constants::CACHE_REDIS_ADDR, formatting::Result},
+};
+
+/// Value and when it expires
+type Store = HashMap<Vec<u8>, (Vec<u8>, Option<Instant>)>;
+
+/// Local stand-in for a Redis server, to run the proxy's redis backend against
+///
+/// In memory, only the commands used by the backend: `PING`, `GET`, `SET` (with `PX`), `DEL`,
+/// `SCAN` (single pass, `MATCH` on a prefix followed by `*`), `DBSIZE`, `INFO` (`used_memory` only),
+/// and `FLUSHALL` to reset it by hand
+fn main() {
+    // i.e. `cargo run --bin resp_store -- 127.0.0.1:6380`
+    let addr = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| CACHE_REDIS_ADDR.to_string());
+    let listener = TcpListener::bind(&addr).unwrap();
+    println!("Listening at: {}", listener.local_addr().unwrap());
+
+    serve(listener);
+}
+
+/// Accept connections, each one is handled on its own thread
+fn serve(listener: TcpListener) {
+    let store = Arc::new(Mutex::new(Store::new()));
+    for connection in listener.incoming() {
+        let stream = match connection {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("Error: handling connection - {}", e);
+                continue;
+            }
+        };
+        let store = Arc::clone(&store);
+
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(stream, &store) {
+                eprintln!("error handling connection: {}", e);
+            }
+        });
+    }
+}
+
+/// Reply to commands until the client closes the connection
+fn handle_connection(stream: TcpStream, store: &Mutex<Store>) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    while let Some(command) = read_value(&mut reader)? {
+        let args: Vec<Vec<u8>> = match command {
+            RespValue::Array(args) => args
+                .into_iter()
+                .filter_map(|arg| match arg {
+                    RespValue::Bulk(Some(arg)) => Some(arg),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        let mut store = store.lock().expect("Poisoned mutex: store");
+        let reply = run_command(&args, &mut store);
+        drop(store);
+
+        write_value(&mut writer, &reply)?;
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+fn run_command(args: &[Vec<u8>], store: &mut Store) -> RespValue {
+    let now = Instant::now();
+    store.retain(|_, (_, expires_at)| expires_at.is_none_or(|at| at > now));
+
+    let name = match args.first() {
+        Some(name) => String::from_utf8_lossy(name).to_ascii_uppercase(),
+        None => return RespValue::Error("ERR empty command".to_string()),
+    };
+    match (name.as_str(), &args[1..]) {
+        ("PING", _) => RespValue::Simple("PONG".to_string()),
+        ("GET", [key]) => RespValue::Bulk(store.get(key).map(|(value, _)| value.clone())),
+        ("SET", [key, value]) => {
+            store.insert(key.clone(), (value.clone(), None));
+            RespValue::Simple("OK".to_string())
+        }
+        ("SET", [key, value, px, ttl_ms]) if px.eq_ignore_ascii_case(b"PX") => {
+            let ttl_ms = match String::from_utf8_lossy(ttl_ms).parse::<u64>() {
+                Ok(ttl_ms) => ttl_ms,
+                Err(_) => return RespValue::Error("ERR invalid expire time".to_string()),
+            };
+            let expires_at = now + Duration::from_millis(ttl_ms);
+            store.insert(key.clone(), (value.clone(), Some(expires_at)));
+            RespValue::Simple("OK".to_string())
+        }
+        ("DEL", keys) => RespValue::Integer(
+            keys.iter()
+                .filter(|key| store.remove(*key).is_some())
+                .count() as i64,
+        ),
+        ("DBSIZE", []) => RespValue::Integer(store.len() as i64),
+        // only `used_memory`, the size of the stored values
+        ("INFO", _) => {
+            let used_memory: usize = store.values().map(|(value, _)| value.len()).sum();
+            RespValue::Bulk(Some(
+                format!("# Memory\r\nused_memory:{used_memory}\r\n").into_bytes(),
+            ))
+        }
+        ("FLUSHALL", []) => {
+            store.clear();
+            RespValue::Simple("OK".to_string())
+        }
+        ("SCAN", [_cursor, options @ ..]) => {
+            // `MATCH <prefix>*`, other options (`COUNT`) are ignored
+            let prefix = options
+                .windows(2)
+                .find(|option| option[0].eq_ignore_ascii_case(b"MATCH"))
+                .map(|option| option[1].strip_suffix(b"*").unwrap_or(&option[1]).to_vec())
+                .unwrap_or_default();
+            let keys = store
+                .keys()
+                .filter(|key| key.starts_with(&prefix))
+                .map(|key| RespValue::Bulk(Some(key.clone())))
+                .collect();
+
+            RespValue::Array(vec![
+                RespValue::Bulk(Some(b"0".to_vec())),
+                RespValue::Array(keys),
+            ])
+        }
+        _ => RespValue::Error(format!("ERR unknown command or arguments '{name}'")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use tcp_proxy::cache_utils::{
+        backend::CacheBackend, clock::SystemClock, config::StalePolicy, entry::CachedEntry,
+        freshness::Freshness, redis::RedisBackend,
+    };
+
+    /// Backend connected to a store on a free port
+    fn backend() -> RedisBackend {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || serve(listener));
+
+        RedisBackend::connect(&addr, Arc::default(), Arc::new(SystemClock)).unwrap()
+    }
+
+    fn entry(body: &'static [u8]) -> Arc<CachedEntry> {
+        let now = Utc::now();
+        let mut res = http::Response::new(bytes::Bytes::from_static(body));
+        res.headers_mut()
+            .insert("cache-control", "max-age=60".parse().unwrap());
+        let freshness = Freshness::from_response(&res, now).unwrap();
+
+        Arc::new(CachedEntry::new(
+            res,
+            freshness,
+            now,
+            &StalePolicy::default(),
+        ))
+    }
+
+    #[test]
+    fn backend_stores_entries() {
+        let backend = backend();
+        assert!(backend.get("GET http://example.com/a").is_none());
+
+        assert!(backend.insert("GET http://example.com/a".to_string(), entry(b"a")));
+        assert!(backend.insert("GET http://example.com/b".to_string(), entry(b"b")));
+        let entry_a = backend.get("GET http://example.com/a").unwrap();
+        assert_eq!(entry_a.response.body().as_ref(), b"a");
+        assert_eq!(entry_a.freshness.lifetime_sec, 60);
+
+        let mut keys = backend.keys();
+        keys.sort();
+        assert_eq!(
+            keys,
+            ["GET http://example.com/a", "GET http://example.com/b"]
+        );
+        let stats = backend.stats();
+        assert_eq!(stats.entries, 2);
+        assert!(stats.size_bytes > 0);
+
+        assert!(backend.remove("GET http://example.com/a"));
+        assert!(!backend.remove("GET http://example.com/a"));
+        assert!(backend.get("GET http://example.com/a").is_none());
+        assert_eq!(backend.keys(), ["GET http://example.com/b"]);
+    }
+
+    #[test]
+    fn entries_past_retention_are_not_stored() {
+        let backend = backend();
+        let now = Utc::now();
+        let mut res = http::Response::new(bytes::Bytes::from_static(b"old"));
+        res.headers_mut()
+            .insert("cache-control", "max-age=60".parse().unwrap());
+        let received_at = now - chrono::Duration::seconds(120);
+        let freshness = Freshness::from_response(&res, received_at).unwrap();
+        let expired = CachedEntry::new(res, freshness, received_at, &StalePolicy::default());
+
+        assert!(!backend.insert("GET http://example.com/old".to_string(), Arc::new(expired)));
+        assert!(backend.keys().is_empty());
+    }
+}
@@ -0,0 +1,270 @@
+// imports
+use chrono::{DateTime, Utc};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    sync::{
+        atomic::AtomicU64,
+        mpsc::{self, Sender},
+        Arc, Mutex, MutexGuard,
+    },
+    thread::{self, JoinHandle},
+};
+// local
+use super::{cache::MemoryBackend, entry::CachedEntry};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// Size of a backend's contents
+pub struct BackendStats {
+    /// Name of the backend, see `CacheBackend::name`
+    pub backend: &'static str,
+    pub entries: usize,
+    /// Total size of the stored entries, in bytes
+    pub size_bytes: u64,
+    /// Amount of entries removed to make room for new ones
+    pub evictions: u64,
+    /// Stats of the backend behind this one, for tiered backends
+    pub lower_tier: Option<Box<BackendStats>>,
+}
+
+/// Storage for cached entries, used by `HTTPCache`
+///
+/// Freshness, `Vary` and request coalescing are handled by `HTTPCache`,
+/// backends only store entries by key and enforce their own capacity.
+pub trait CacheBackend: Send + Sync + Debug {
+    /// Name of the backend in logs and stats, i.e. `memory`
+    fn name(&self) -> &'static str;
+    /// Get an entry, fresh or stale. Backends with an eviction order mark it as recently used
+    fn get(&self, key: &str) -> Option<Arc<CachedEntry>>;
+    /// Insert an entry, replacing the one with the same key.
+    ///
+    /// Returns false if the entry was not stored (i.e. bigger than the backend's budget)
+    fn insert(&self, key: String, entry: Arc<CachedEntry>) -> bool;
+    /// Remove an entry, returns true if removed
+    fn remove(&self, key: &str) -> bool;
+    /// Keys of every stored entry
+    fn keys(&self) -> Vec<String>;
+    /// Every stored entry with its key
+    fn entries(&self) -> Vec<(String, Arc<CachedEntry>)> {
+        self.keys()
+            .into_iter()
+            .filter_map(|key| self.get(&key).map(|entry| (key, entry)))
+            .collect()
+    }
+    /// Remove the entries no longer retained at `now`, returns the amount removed
+    fn purge_expired(&self, now: DateTime<Utc>) -> usize;
+    /// An entry of `size_bytes` can be inserted under `key` without evicting others
+    fn has_room_for(&self, _key: &str, _size_bytes: usize) -> bool {
+        true
+    }
+    fn stats(&self) -> BackendStats;
+}
+
+#[derive(Debug, Default)]
+/// Hit counts of the entries of a backend that rebuilds them on every read (disk, redis)
+///
+/// Without it every read would return an entry with no hits, and refresh-ahead would never trigger
+pub struct HitCounters {
+    /// Counter of each key, with the insertion time and retention of the entry it counts
+    counters: Mutex<HashMap<String, HitCounter>>,
+}
+
+#[derive(Debug)]
+struct HitCounter {
+    hits: Arc<AtomicU64>,
+    inserted_at: DateTime<Utc>,
+    retain_until: DateTime<Utc>,
+}
+
+impl HitCounters {
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, HitCounter>> {
+        self.counters.lock().expect("Poisoned mutex: hit counters")
+    }
+    /// Share the key's counter with an entry read back from the backend
+    ///
+    /// The counter starts over when the entry was replaced (i.e. by another proxy sharing a redis server)
+    pub fn attach(&self, key: &str, entry: CachedEntry) -> CachedEntry {
+        let mut counters = self.lock();
+        let is_same_entry = counters
+            .get(key)
+            .is_some_and(|counter| counter.inserted_at == entry.inserted_at);
+        if !is_same_entry {
+            counters.insert(
+                key.to_string(),
+                HitCounter {
+                    hits: Arc::default(),
+                    inserted_at: entry.inserted_at,
+                    retain_until: entry.retain_until,
+                },
+            );
+        }
+        let hits = Arc::clone(&counters[key].hits);
+
+        entry.with_hit_counter(hits)
+    }
+    pub fn remove(&self, key: &str) {
+        self.lock().remove(key);
+    }
+    /// Drop the counters of entries no longer retained at `now`
+    pub fn purge(&self, now: DateTime<Utc>) {
+        self.lock().retain(|_, counter| counter.retain_until > now);
+    }
+}
+
+#[derive(Debug)]
+/// In-memory backend in front of a slower one (disk, redis)
+///
+/// Entries are written to both, entries missing from memory are read from the lower tier
+/// and loaded back into memory. Entries evicted from memory stay available in the lower tier.
+/// Writes to the lower tier are made by a background thread, so clients are not kept waiting on them.
+pub struct TieredBackend {
+    memory: MemoryBackend,
+    lower: Arc<dyn CacheBackend>,
+    /// Queue of the lower tier's writer thread, taken on drop to stop it
+    lower_writes: Mutex<Option<Sender<LowerWrite>>>,
+    lower_writer: Option<JoinHandle<()>>,
+}
+
+#[derive(Debug)]
+/// Write made to the lower tier by `TieredBackend`'s writer thread
+enum LowerWrite {
+    Insert(String, Arc<CachedEntry>),
+    Remove(String),
+}
+
+impl TieredBackend {
+    /// Put `memory` in front of `lower`, loading the lower tier's entries into memory as long as they fit
+    pub fn new(memory: MemoryBackend, lower: Arc<dyn CacheBackend>, now: DateTime<Utc>) -> Self {
+        let mut lower_entries = lower.entries();
+        let amt_stored = lower_entries.len();
+        lower_entries.retain(|(_, entry)| entry.is_retained(now));
+        // most recently inserted first, in case they do not all fit in memory
+        lower_entries.sort_by_key(|(_, entry)| std::cmp::Reverse(entry.inserted_at));
+
+        let mut amt_loaded = 0;
+        for (key, entry) in lower_entries {
+            if memory.has_room_for(&key, entry.size_bytes) && memory.insert(key, entry) {
+                amt_loaded += 1;
+            }
+        }
+        println!(
+            "Loaded {amt_loaded} entries from {} cache ({amt_stored} stored)",
+            lower.name()
+        );
+
+        // writes are applied in order, so a removal is never undone by an earlier insert
+        let (lower_writes, queued_writes) = mpsc::channel();
+        let writer_lower = Arc::clone(&lower);
+        let lower_writer = thread::spawn(move || {
+            for write in queued_writes {
+                match write {
+                    LowerWrite::Insert(key, entry) => {
+                        writer_lower.insert(key, entry);
+                    }
+                    LowerWrite::Remove(key) => {
+                        writer_lower.remove(&key);
+                    }
+                }
+            }
+        });
+
+        Self {
+            memory,
+            lower,
+            lower_writes: Mutex::new(Some(lower_writes)),
+            lower_writer: Some(lower_writer),
+        }
+    }
+    /// Queue a write to the lower tier, false if the writer thread stopped
+    fn queue_lower_write(&self, write: LowerWrite) -> bool {
+        let lower_writes = self
+            .lower_writes
+            .lock()
+            .expect("Poisoned mutex: lower tier writes");
+
+        match lower_writes.as_ref().map(|sender| sender.send(write)) {
+            Some(Ok(())) => true,
+            _ => {
+                eprintln!(
+                    "{} cache writer stopped... skipping write",
+                    self.lower.name()
+                );
+                false
+            }
+        }
+    }
+}
+
+impl Drop for TieredBackend {
+    /// Wait for the queued writes to reach the lower tier
+    fn drop(&mut self) {
+        if let Ok(mut lower_writes) = self.lower_writes.lock() {
+            lower_writes.take();
+        }
+        if let Some(lower_writer) = self.lower_writer.take() {
+            lower_writer.join().ok();
+        }
+    }
+}
+
+impl CacheBackend for TieredBackend {
+    fn name(&self) -> &'static str {
+        "tiered"
+    }
+    fn get(&self, key: &str) -> Option<Arc<CachedEntry>> {
+        if let Some(entry) = self.memory.get(key) {
+            return Some(entry);
+        }
+
+        let entry = self.lower.get(key)?;
+        println!(
+            "{} cache hit... loaded entry into memory",
+            self.lower.name()
+        );
+        self.memory.insert(key.to_string(), Arc::clone(&entry));
+
+        Some(entry)
+    }
+    fn insert(&self, key: String, entry: Arc<CachedEntry>) -> bool {
+        let is_queued_lower =
+            self.queue_lower_write(LowerWrite::Insert(key.clone(), Arc::clone(&entry)));
+
+        self.memory.insert(key, entry) || is_queued_lower
+    }
+    /// Removed from the lower tier right away, and again once the queued writes before it are applied
+    fn remove(&self, key: &str) -> bool {
+        let is_removed_lower = self.lower.remove(key);
+        self.queue_lower_write(LowerWrite::Remove(key.to_string()));
+
+        self.memory.remove(key) || is_removed_lower
+    }
+    fn keys(&self) -> Vec<String> {
+        let mut keys: HashSet<String> = self.lower.keys().into_iter().collect();
+        keys.extend(self.memory.keys());
+
+        keys.into_iter().collect()
+    }
+    /// Entries of the lower tier, with the ones in memory taking precedence. Nothing is loaded into memory
+    fn entries(&self) -> Vec<(String, Arc<CachedEntry>)> {
+        let mut entries: HashMap<String, Arc<CachedEntry>> =
+            self.lower.entries().into_iter().collect();
+        entries.extend(self.memory.entries());
+
+        entries.into_iter().collect()
+    }
+    fn purge_expired(&self, now: DateTime<Utc>) -> usize {
+        let amt_purged_lower = self.lower.purge_expired(now);
+
+        self.memory.purge_expired(now).max(amt_purged_lower)
+    }
+    fn has_room_for(&self, key: &str, size_bytes: usize) -> bool {
+        self.memory.has_room_for(key, size_bytes)
+    }
+    fn stats(&self) -> BackendStats {
+        BackendStats {
+            backend: self.name(),
+            lower_tier: Some(Box::new(self.lower.stats())),
+            ..self.memory.stats()
+        }
+    }
+}
@@ -1,92 +1,825 @@
 // imports
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
 use http::{Request, Response};
 use std::{
-    collections::HashMap,
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
     sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard},
 };
 // local
-pub use crate::http_utils::{constants::*, errors::Result};
+use super::{
+    backend::{BackendStats, CacheBackend, TieredBackend},
+    clock::{Clock, SystemClock},
+    coalesce::{RequestCoalescer, Revalidation},
+    config::{BackendKind, CacheConfig, EvictionKind},
+    disk::DiskBackend,
+    entry::CachedEntry,
+    eviction::{new_policy, select_victims, EvictionPolicy, PolicyComparison, PolicyHitRatio},
+    key::{vary_header_names, CacheKey},
+    purge::{surrogate_keys, PurgeScope},
+    redis::RedisBackend,
+    stats::{CacheCounters, CacheEvent, CacheStats},
+    stored::StoredEntry,
+};
+pub use crate::http_utils::{
+    constants::*,
+    errors::{fmt_error, Result},
+};
 
-/// Bytes array
-pub type ResBody = Vec<u8>;
-pub type MapValue = Response<Vec<u8>>;
-pub type Cache = HashMap<String, Mutex<MapValue>>;
+/// Bytes array, reference counted so cached bodies are cheap to clone
+pub type ResBody = Bytes;
+pub type MapValue = Response<ResBody>;
+pub type CacheMap = HashMap<String, Arc<CachedEntry>>;
+/// Header names from the stored response's `Vary`, by primary key (method and url)
+pub type VaryIndex = HashMap<String, Vec<String>>;
 
-#[derive(Debug, Default)]
-/// An instance of a thread-safe cache for the proxy server.
+#[derive(Debug)]
+/// Result of looking up a key in the cache
+pub enum Lookup {
+    /// Entry can be served as is
+    Fresh(Arc<CachedEntry>),
+    /// Entry is expired, but kept so it can be revalidated with origin
+    Stale(Arc<CachedEntry>),
+    Miss,
+}
+
+#[derive(Debug)]
+/// Map of cached responses bounded in bytes, evicts entries chosen by its policy when over budget
 ///
-/// type is:
-/// HTTPCache = Arc<RwLock<Cache>>\
-/// Cache = HashMap<String, Mutex<MapValue>>\
-/// MapValue = Response<Vec<u8>>
+/// A single shard of `MemoryBackend`
+pub struct Cache {
+    entries: CacheMap,
+    /// Eviction order of the keys, updated on reads so it sits behind its own lock
+    policy: Mutex<Box<dyn EvictionPolicy>>,
+    /// Byte budget for the headers and bodies of all entries
+    max_bytes: usize,
+    /// Total size of the entries, in bytes
+    size_bytes: usize,
+    /// Amount of entries removed to make room for new ones
+    evictions: u64,
+}
+
+impl Cache {
+    pub fn new(max_bytes: usize, policy: Box<dyn EvictionPolicy>) -> Self {
+        Self {
+            entries: CacheMap::new(),
+            policy: Mutex::new(policy),
+            max_bytes,
+            size_bytes: 0,
+            evictions: 0,
+        }
+    }
+    /// Get an entry without updating the eviction order or its hits, fresh or stale
+    pub fn peek(&self, key: &str) -> Option<&Arc<CachedEntry>> {
+        self.entries.get(key)
+    }
+    /// Get an entry, fresh or stale, and record the access in the eviction order
+    pub fn get(&self, key: &str) -> Option<&Arc<CachedEntry>> {
+        let entry = self.entries.get(key)?;
+        self.policy
+            .lock()
+            .expect("Poisoned mutex: updating eviction order")
+            .on_access(key);
+
+        Some(entry)
+    }
+    /// Insert an entry, evicting the entries chosen by the policy until it fits in the byte budget.
+    ///
+    /// If the key already exists, the existing entry is replaced.
+    /// Returns false (nothing inserted) if the entry is bigger than the whole budget,
+    /// or if the policy rejects it in favor of the entries already cached
+    pub fn insert(&mut self, key: String, entry: Arc<CachedEntry>) -> bool {
+        if entry.size_bytes > self.max_bytes {
+            return false;
+        }
+        // the replaced entry does not count against the budget, but keeps its place in the eviction order
+        let policy = self
+            .policy
+            .get_mut()
+            .expect("Poisoned mutex: updating eviction order");
+        let replaced_size = self.entries.get(&key).map(|replaced| replaced.size_bytes);
+        match replaced_size {
+            Some(_) => policy.on_access(&key),
+            None => policy.on_insert(&key),
+        }
+        // victims are only removed once the policy admitted the entry, a rejected one leaves the shard as is
+        let bytes_needed = (self.size_bytes - replaced_size.unwrap_or(0) + entry.size_bytes)
+            .saturating_sub(self.max_bytes);
+        let entries = &self.entries;
+        let victims = select_victims(policy.as_mut(), &key, bytes_needed, |victim| {
+            entries
+                .get(victim)
+                .map_or(0, |victim_entry| victim_entry.size_bytes)
+        });
+        let Some(victims) = victims else {
+            // the entry it would have replaced is kept, and tracked again
+            if replaced_size.is_some() {
+                policy.on_insert(&key);
+            }
+            return false;
+        };
+        for victim in victims {
+            if let Some(victim_entry) = self.entries.remove(&victim) {
+                self.size_bytes -= victim_entry.size_bytes;
+                self.evictions += 1;
+                println!("Cache full - evicted entry: {victim}");
+            }
+        }
+        if let Some(replaced) = self.entries.insert(key, Arc::clone(&entry)) {
+            self.size_bytes -= replaced.size_bytes;
+        }
+        self.size_bytes += entry.size_bytes;
+
+        true
+    }
+    /// Remove an entry, returns it if it was in the cache
+    pub fn remove(&mut self, key: &str) -> Option<Arc<CachedEntry>> {
+        let entry = self.entries.remove(key)?;
+        self.size_bytes -= entry.size_bytes;
+        self.policy
+            .get_mut()
+            .expect("Poisoned mutex: updating eviction order")
+            .on_remove(key);
+
+        Some(entry)
+    }
+    /// Remove an entry if it is past its expiry and no longer retained for revalidation,
+    /// returns true if removed
+    pub fn remove_expired(&mut self, key: &str, now: DateTime<Utc>) -> bool {
+        let is_expired = match self.entries.get(key) {
+            Some(entry) => !entry.is_retained(now),
+            None => false,
+        };
+        if is_expired {
+            self.remove(key);
+        }
+
+        is_expired
+    }
+    /// Remove the entry chosen by the eviction policy, returns its key
+    pub fn evict(&mut self) -> Option<String> {
+        let key = self
+            .policy
+            .get_mut()
+            .expect("Poisoned mutex: updating eviction order")
+            .pop_victim()?;
+        if let Some(entry) = self.entries.remove(&key) {
+            self.size_bytes -= entry.size_bytes;
+            self.evictions += 1;
+        }
+
+        Some(key)
+    }
+    /// Iterate over the entries, fresh or stale, without updating the eviction order
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Arc<CachedEntry>)> {
+        self.entries.iter()
+    }
+    /// Keep only the entries for which `keep` returns true
+    pub fn retain<F>(&mut self, mut keep: F)
+    where
+        F: FnMut(&String, &CachedEntry) -> bool,
+    {
+        let policy = self
+            .policy
+            .get_mut()
+            .expect("Poisoned mutex: updating eviction order");
+        let size_bytes = &mut self.size_bytes;
+        self.entries.retain(|key, entry| {
+            let is_kept = keep(key, entry);
+            if !is_kept {
+                policy.on_remove(key);
+                *size_bytes -= entry.size_bytes;
+            }
+            is_kept
+        });
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    /// Total size of the entries, in bytes
+    pub fn size_bytes(&self) -> usize {
+        self.size_bytes
+    }
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+    /// An entry of `size_bytes` can be inserted without evicting others
+    pub fn has_room_for(&self, size_bytes: usize) -> bool {
+        self.size_bytes + size_bytes <= self.max_bytes
+    }
+    /// Amount of entries evicted since the cache was created
+    pub fn evictions(&self) -> u64 {
+        self.evictions
+    }
+}
+
+#[derive(Debug)]
+/// In-memory backend: the cache split into shards
 ///
-/// Arc<RwLock<HashMap<String, Mutex<Response<\Vec<u8\> \>\>\>\>\>
-pub struct HTTPCache(Arc<RwLock<Cache>>);
-/// Instance of read lock for the cache
+/// Keys are spread across shards by hash, so requests on different keys rarely wait on the same lock.
+/// Locks are only held for the map operation: entries are handed out as `Arc`s,
+/// so writing them to a socket happens without any lock.
+pub struct MemoryBackend {
+    shards: Vec<RwLock<Cache>>,
+}
+/// Instance of read lock for a cache shard
 pub struct CacheReadLock<'a> {
     pub guard: RwLockReadGuard<'a, Cache>,
 }
-/// Instance of a write lock for the cache
+/// Instance of a write lock for a cache shard
 pub struct CacheWriteLock<'a> {
     pub guard: RwLockWriteGuard<'a, Cache>,
 }
-impl<'a> CacheReadLock<'a> {
-    /// Get entry from the hashmap (cache)
-    pub fn get(&self, key: &String) -> Option<&Mutex<MapValue>> {
-        self.guard.get(key).map(|s| s.clone())
+impl CacheReadLock<'_> {
+    /// Get entry from the hashmap (cache), updates the eviction order
+    pub fn get(&self, key: &str) -> Option<&Arc<CachedEntry>> {
+        self.guard.get(key)
     }
 }
-impl<'a> CacheWriteLock<'a> {
-    /// Insert an entry into the cache
-    pub fn insert(
-        lock_guard: &'a mut RwLockWriteGuard<HashMap<String, Mutex<Response<Vec<u8>>>>>,
-        key: String,
-        entry: Response<Vec<u8>>,
-    ) -> &'a mut Mutex<Response<Vec<u8>>> {
-        let map_entry = lock_guard.entry(key);
-        let inserted_value = map_entry.or_insert_with(|| Mutex::new(entry));
+impl CacheWriteLock<'_> {
+    /// Insert an entry into the cache, false if it does not fit in the shard's byte budget
+    pub fn insert(&mut self, key: String, entry: Arc<CachedEntry>) -> bool {
+        self.guard.insert(key, entry)
+    }
+}
+
+impl MemoryBackend {
+    /// Create a backend holding at most `max_bytes`, split into `amt_shards` shards.
+    ///
+    /// The byte budget is split evenly, each shard evicts on its own with its own `eviction` policy
+    pub fn new(max_bytes: usize, amt_shards: usize, eviction: EvictionKind) -> Self {
+        let amt_shards = amt_shards.max(1);
+        let max_bytes_per_shard = max_bytes.div_ceil(amt_shards);
 
-        inserted_value
+        Self {
+            shards: (0..amt_shards)
+                .map(|_| RwLock::new(Cache::new(max_bytes_per_shard, new_policy(eviction))))
+                .collect(),
+        }
     }
+    /// Index of the shard holding `key`
+    fn shard_idx(&self, key: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
 
-    /// Simple wrapper for Self::insert
-    pub fn insert_req(
-        lock: &'a mut CacheWriteLock,
-        req: Request<Vec<u8>>,
-        entry: Response<Vec<u8>>,
-    ) -> &'a mut Mutex<Response<Vec<u8>>> {
-        let key = String::from_utf8(req.body().to_vec()).unwrap().clone();
+        (hasher.finish() % self.shards.len() as u64) as usize
+    }
+    /// Initialize the lock for writing, on the shard holding `key`
+    pub fn lock_write(&self, key: &str) -> CacheWriteLock<'_> {
+        self.lock_write_shard(self.shard_idx(key))
+    }
+    /// Initialize the lock for reading, on the shard holding `key`
+    pub fn lock_read(&self, key: &str) -> CacheReadLock<'_> {
+        self.lock_read_shard(self.shard_idx(key))
+    }
+    fn lock_write_shard(&self, shard_idx: usize) -> CacheWriteLock<'_> {
+        CacheWriteLock {
+            guard: self.shards[shard_idx]
+                .write()
+                .expect("Poisoned write lock (RwLock)"),
+        }
+    }
+    fn lock_read_shard(&self, shard_idx: usize) -> CacheReadLock<'_> {
+        CacheReadLock {
+            guard: self.shards[shard_idx]
+                .read()
+                .expect("Poisoned read lock (RwLock)"),
+        }
+    }
+}
 
-        // insert and return
-        Self::insert(&mut lock.guard, key, entry)
+impl CacheBackend for MemoryBackend {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+    fn get(&self, key: &str) -> Option<Arc<CachedEntry>> {
+        self.lock_read(key).get(key).map(Arc::clone)
+    }
+    fn insert(&self, key: String, entry: Arc<CachedEntry>) -> bool {
+        self.lock_write(&key).insert(key, entry)
+    }
+    fn remove(&self, key: &str) -> bool {
+        self.lock_write(key).guard.remove(key).is_some()
+    }
+    fn keys(&self) -> Vec<String> {
+        (0..self.shards.len())
+            .flat_map(|shard_idx| {
+                self.lock_read_shard(shard_idx)
+                    .guard
+                    .iter()
+                    .map(|(key, _)| key.clone())
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+    /// Copy of every entry with its key, one shard locked at a time, without updating the eviction order
+    fn entries(&self) -> Vec<(String, Arc<CachedEntry>)> {
+        (0..self.shards.len())
+            .flat_map(|shard_idx| {
+                self.lock_read_shard(shard_idx)
+                    .guard
+                    .iter()
+                    .map(|(key, entry)| (key.clone(), Arc::clone(entry)))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+    /// Shards are locked one at a time, so readers on other shards are not blocked
+    fn purge_expired(&self, now: DateTime<Utc>) -> usize {
+        (0..self.shards.len())
+            .map(|shard_idx| {
+                let mut lock_w = self.lock_write_shard(shard_idx);
+                let init_len = lock_w.guard.len();
+                lock_w.guard.retain(|_, entry| entry.is_retained(now));
+
+                init_len - lock_w.guard.len()
+            })
+            .sum()
+    }
+    fn has_room_for(&self, key: &str, size_bytes: usize) -> bool {
+        self.lock_read(key).guard.has_room_for(size_bytes)
+    }
+    fn stats(&self) -> BackendStats {
+        let mut stats = BackendStats {
+            backend: self.name(),
+            ..BackendStats::default()
+        };
+        for shard_idx in 0..self.shards.len() {
+            let lock_r = self.lock_read_shard(shard_idx);
+            stats.entries += lock_r.guard.len();
+            stats.size_bytes += lock_r.guard.size_bytes() as u64;
+            stats.evictions += lock_r.guard.evictions();
+        }
+
+        stats
+    }
+}
+
+#[derive(Debug, Clone)]
+/// An instance of a thread-safe cache for the proxy server.
+///
+/// type is:
+/// HTTPCache = Arc<dyn CacheBackend> + vary index + in-flight origin fetches and revalidations + counters + clock + config\
+/// CacheBackend = memory (shards of bounded HashMap<String, Arc<CachedEntry>>), disk, redis, or memory in front of disk\
+/// CachedEntry = Response<Bytes> + expiry/access metadata
+///
+/// Keys are `CacheKey` strings. The vary index tells which request headers are part of the key for a url.
+///
+/// Freshness is decided here, backends only store entries (see `CacheBackend`)
+pub struct HTTPCache {
+    backend: Arc<dyn CacheBackend>,
+    vary_index: Arc<RwLock<VaryIndex>>,
+    in_flight: Arc<RequestCoalescer>,
+    revalidations: Arc<RequestCoalescer<Revalidation>>,
+    counters: Arc<CacheCounters>,
+    /// Shadow caches replaying requests on every eviction policy, if enabled
+    comparison: Option<Arc<Mutex<PolicyComparison>>>,
+    /// Source of `now` for callers deciding freshness and expiry, see `HTTPCache::now`
+    clock: Arc<dyn Clock>,
+    config: Arc<CacheConfig>,
+}
+
+impl Default for HTTPCache {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl HTTPCache {
-    /// Create a new instance of HTTPCache
-    /// type Arc<RwLock<HashMap<String, Mutex<Response<Vec<u8\>\>\>\>\>\>
+    /// Create a new instance of HTTPCache, bounded to `CACHE_MAX_BYTES`
     pub fn new() -> Self {
-        let new_instance = Cache::new();
-        let new_instance = RwLock::new(new_instance);
-        let new_instance: Arc<RwLock<HashMap<String, Mutex<Response<Vec<u8>>>>>> =
-            Arc::new(new_instance);
+        Self::with_config(CacheConfig::default())
+    }
+    /// Create a new instance of HTTPCache holding at most `max_bytes` of responses
+    pub fn with_max_bytes(max_bytes: usize) -> Self {
+        Self::with_shards(max_bytes, CACHE_SHARDS)
+    }
+    /// Create a new in-memory instance of HTTPCache with runtime config, bounded to its `max_bytes`
+    pub fn with_config(config: CacheConfig) -> Self {
+        let memory = MemoryBackend::new(config.max_bytes, CACHE_SHARDS, config.eviction);
 
-        Self(new_instance)
+        Self::with_backend(Arc::new(memory), config)
     }
-    /// Simple wrapper for clone
-    pub fn clone(&self) -> Self {
-        Self(Arc::clone(&self.0))
+    /// Create a new in-memory instance of HTTPCache split into `amt_shards` shards.
+    pub fn with_shards(max_bytes: usize, amt_shards: usize) -> Self {
+        let config = CacheConfig {
+            max_bytes,
+            ..CacheConfig::default()
+        };
+
+        let memory = MemoryBackend::new(max_bytes, amt_shards, config.eviction);
+
+        Self::with_backend(Arc::new(memory), config)
     }
-    /// Initialize the lock for writing
-    pub fn lock_write(&self) -> CacheWriteLock {
-        CacheWriteLock {
-            guard: self.0.write().expect("Poisoned write lock (RwLock)"),
+    /// Create a new instance of HTTPCache on top of any backend
+    pub fn with_backend(backend: Arc<dyn CacheBackend>, config: CacheConfig) -> Self {
+        let comparison = config
+            .eviction_compare
+            .then(|| Arc::new(Mutex::new(PolicyComparison::new(config.max_bytes))));
+
+        Self {
+            backend,
+            vary_index: Arc::new(RwLock::new(VaryIndex::new())),
+            in_flight: Arc::new(RequestCoalescer::new()),
+            revalidations: Arc::new(RequestCoalescer::new()),
+            counters: Arc::new(CacheCounters::default()),
+            comparison,
+            clock: Arc::new(SystemClock),
+            config: Arc::new(config),
         }
     }
-    /// Initialize the lock for reading
-    pub fn lock_read(&self) -> CacheReadLock {
-        CacheReadLock {
-            guard: self.0.read().expect("Poisoned read lock (RwLock)"),
+    /// Replace the system clock, i.e. with a `MockClock` to control expiry in tests
+    ///
+    /// Backends opened by `open` keep their clock, use `open_with_clock` for those
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> Self {
+        Self { clock, ..self }
+    }
+    /// Create a new instance of HTTPCache with the backend selected in the config
+    ///
+    /// 1) `memory`: in memory, in front of the disk tier if `disk_dir` is set.
+    ///    Entries still retained on disk are loaded back into memory, as long as they fit in its byte budget
+    /// 1) `disk`: disk only, `disk_dir` is required
+    /// 1) `redis`: a Redis-protocol server at `redis_addr`, shareable between proxies
+    ///
+    /// The vary index is rebuilt from the keys already stored
+    pub fn open(config: CacheConfig) -> Result<Self> {
+        Self::open_with_clock(config, Arc::new(SystemClock))
+    }
+    /// Same as `open`, with `clock` deciding which stored entries are still retained
+    pub fn open_with_clock(config: CacheConfig, clock: Arc<dyn Clock>) -> Result<Self> {
+        let config_arc = Arc::new(config.clone());
+        let backend: Arc<dyn CacheBackend> = match (&config.backend, &config.disk_dir) {
+            (BackendKind::Memory, None) => Arc::new(MemoryBackend::new(
+                config.max_bytes,
+                CACHE_SHARDS,
+                config.eviction,
+            )),
+            (BackendKind::Memory, Some(dir)) => {
+                let disk = DiskBackend::open(dir, config.disk_max_bytes, Arc::clone(&config_arc))?;
+                Arc::new(TieredBackend::new(
+                    MemoryBackend::new(config.max_bytes, CACHE_SHARDS, config.eviction),
+                    Arc::new(disk),
+                    clock.now(),
+                ))
+            }
+            (BackendKind::Disk, Some(dir)) => Arc::new(DiskBackend::open(
+                dir,
+                config.disk_max_bytes,
+                Arc::clone(&config_arc),
+            )?),
+            (BackendKind::Disk, None) => {
+                return Err(fmt_error("--disk-dir", "Disk backend requires"));
+            }
+            (BackendKind::Redis, _) => Arc::new(RedisBackend::connect(
+                &config.redis_addr,
+                Arc::clone(&config_arc),
+                Arc::clone(&clock),
+            )?),
+        };
+        let cache = Self::with_backend(backend, config).with_clock(clock);
+
+        let mut vary_index = cache
+            .vary_index
+            .write()
+            .expect("Poisoned write lock (RwLock)");
+        for key in cache
+            .backend
+            .keys()
+            .iter()
+            .filter_map(|key| CacheKey::parse(key))
+        {
+            if !key.vary.is_empty() {
+                let vary_names = key.vary.iter().map(|(name, _)| name.clone()).collect();
+                vary_index.insert(key.primary().to_string(), vary_names);
+            }
+        }
+        drop(vary_index);
+
+        Ok(cache)
+    }
+    /// Current time according to the cache's clock
+    pub fn now(&self) -> DateTime<Utc> {
+        self.clock.now()
+    }
+    /// Runtime config the cache was created with
+    pub fn config(&self) -> &CacheConfig {
+        &self.config
+    }
+    /// Storage the entries are kept in
+    pub fn backend(&self) -> &dyn CacheBackend {
+        self.backend.as_ref()
+    }
+    /// Origin fetches in progress, used to coalesce concurrent misses on the same key
+    pub fn in_flight(&self) -> &RequestCoalescer {
+        &self.in_flight
+    }
+    /// Revalidations in progress, used to coalesce concurrent revalidations of the same key
+    pub fn revalidations(&self) -> &RequestCoalescer<Revalidation> {
+        &self.revalidations
+    }
+    /// Get an entry without recording a hit on it, fresh or stale
+    pub fn peek(&self, key: &str) -> Option<Arc<CachedEntry>> {
+        self.backend.get(key)
+    }
+    /// Hit, miss and eviction counters, along with the backend's current size
+    /// and the hit ratio of every eviction policy (if compared)
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            policy_hit_ratios: self.policy_hit_ratios(),
+            ..self.counters.snapshot(self.backend.stats())
+        }
+    }
+    /// Hit ratio every eviction policy would have had on the requests so far, empty if not compared
+    pub fn policy_hit_ratios(&self) -> Vec<PolicyHitRatio> {
+        match &self.comparison {
+            Some(comparison) => comparison
+                .lock()
+                .expect("Poisoned mutex: eviction policy comparison")
+                .hit_ratios(),
+            None => Vec::new(),
+        }
+    }
+    /// Run `replay` on the eviction policy comparison, if enabled
+    fn compare<F>(&self, replay: F)
+    where
+        F: FnOnce(&mut PolicyComparison),
+    {
+        if let Some(comparison) = &self.comparison {
+            replay(
+                &mut comparison
+                    .lock()
+                    .expect("Poisoned mutex: eviction policy comparison"),
+            );
+        }
+    }
+    /// Get an unexpired entry and record the hit on it
+    pub fn get(&self, key: &str, now: DateTime<Utc>) -> Option<Arc<CachedEntry>> {
+        match self.lookup(key, now) {
+            Lookup::Fresh(entry) => Some(entry),
+            _ => None,
+        }
+    }
+    /// Look up an entry, returning expired entries that are still retained as stale.
+    ///
+    /// Hits are only recorded on fresh entries, every lookup is counted in `stats`
+    pub fn lookup(&self, key: &str, now: DateTime<Utc>) -> Lookup {
+        let lookup = match self.backend.get(key) {
+            Some(entry) if !entry.is_expired(now) => {
+                entry.record_hit(now);
+                Lookup::Fresh(entry)
+            }
+            Some(entry) if entry.is_retained(now) => Lookup::Stale(entry),
+            _ => Lookup::Miss,
+        };
+        self.counters.record(match lookup {
+            Lookup::Fresh(_) => CacheEvent::Hit,
+            Lookup::Stale(_) => CacheEvent::StaleHit,
+            Lookup::Miss => CacheEvent::Miss,
+        });
+        let found = match &lookup {
+            Lookup::Fresh(entry) | Lookup::Stale(entry) => {
+                Some((entry.size_bytes, entry.retain_until))
+            }
+            Lookup::Miss => None,
+        };
+        self.compare(|comparison| comparison.record_lookup(key, now, found));
+
+        lookup
+    }
+    /// Insert an entry into the backend
+    ///
+    /// Returns None (nothing inserted) if the entry is bigger than `max_object_bytes`,
+    /// such responses are served but not cached
+    pub fn insert(&self, key: String, entry: CachedEntry) -> Option<Arc<CachedEntry>> {
+        if entry.size_bytes > self.config.max_object_bytes {
+            println!(
+                "response is too large to cache ({} bytes)... skipping cache",
+                entry.size_bytes
+            );
+            return None;
+        }
+        let entry = Arc::new(entry);
+        if !self.backend.insert(key.clone(), Arc::clone(&entry)) {
+            return None;
+        }
+        self.compare(|comparison| {
+            comparison.record_insert(&key, entry.size_bytes, entry.retain_until)
+        });
+        self.counters.record(CacheEvent::Insertion);
+        if let Some(uncompressed_body_len) = entry.uncompressed_body_len {
+            self.counters
+                .record_compression(uncompressed_body_len, entry.response.body().len());
+        }
+
+        Some(entry)
+    }
+    /// Insert a stored entry (snapshot) into the backend, along with its `Vary` headers
+    ///
+    /// If `evict` is false, the entry is only inserted if the backend has room for it
+    pub fn restore(&self, stored: StoredEntry, evict: bool) -> Result<Option<Arc<CachedEntry>>> {
+        let primary_key = CacheKey::parse(&stored.key)
+            .ok_or_else(|| fmt_error(&stored.key, "Invalid stored cache key"))?
+            .primary();
+        let key = stored.key.clone();
+        let entry = stored.into_entry(self.config.stale_policy(&primary_key.url))?;
+
+        let vary_names = vary_header_names(entry.response.headers());
+        if !vary_names.is_empty() {
+            self.vary_index
+                .write()
+                .expect("Poisoned write lock (RwLock)")
+                .insert(primary_key.to_string(), vary_names);
+        }
+
+        if !evict && !self.backend.has_room_for(&key, entry.size_bytes) {
+            return Ok(None);
+        }
+        let entry = Arc::new(entry);
+
+        Ok(self
+            .backend
+            .insert(key, Arc::clone(&entry))
+            .then_some(entry))
+    }
+    /// Cache key for a request: method, canonical target url,
+    /// and the request headers listed in the stored response's `Vary`
+    pub fn key_for_request(&self, req: &Request<Vec<u8>>) -> Result<String> {
+        let primary_key = CacheKey::from_request(req)?;
+        let vary_index = self.vary_index.read().expect("Poisoned read lock (RwLock)");
+
+        let key = match vary_index.get(&primary_key.to_string()) {
+            Some(vary_names) => primary_key.with_vary(vary_names, req.headers()),
+            None => primary_key,
+        };
+
+        Ok(key.to_string())
+    }
+    /// Wrapper for Self::insert, the key is built from the request and the response's `Vary`
+    pub fn insert_req(
+        &self,
+        req: &Request<Vec<u8>>,
+        entry: CachedEntry,
+    ) -> Result<Option<Arc<CachedEntry>>> {
+        let primary_key = CacheKey::from_request(req)?;
+        let vary_names = vary_header_names(entry.response.headers());
+
+        // later lookups on the url use the latest response's `Vary`
+        let mut vary_index = self
+            .vary_index
+            .write()
+            .expect("Poisoned write lock (RwLock)");
+        if vary_names.is_empty() {
+            vary_index.remove(&primary_key.to_string());
+        } else {
+            vary_index.insert(primary_key.to_string(), vary_names.clone());
+        }
+        drop(vary_index);
+        let key = primary_key.with_vary(&vary_names, req.headers());
+
+        // insert and return
+        Ok(self.insert(key.to_string(), entry))
+    }
+    /// Remove an entry on request, returns true if removed
+    pub fn remove(&self, key: &str) -> bool {
+        let is_removed = self.backend.remove(key);
+        if is_removed {
+            self.compare(|comparison| comparison.record_remove(key));
+            self.counters.record(CacheEvent::EvictionPurge);
+        }
+
+        is_removed
+    }
+    /// Remove the entries matching a `PURGE` request, returns the amount removed
+    ///
+    /// Prefixes and tags are matched against every stored entry
+    pub fn purge(&self, scope: &PurgeScope) -> usize {
+        let target_url_matches = |key: &String, is_match: &dyn Fn(&str) -> bool| {
+            CacheKey::parse(key).is_some_and(|key| is_match(&key.url))
+        };
+        let keys: Vec<String> = match scope {
+            // every variant of the url
+            PurgeScope::Key(target_url) => self
+                .backend
+                .keys()
+                .into_iter()
+                .filter(|key| target_url_matches(key, &|url| url == target_url))
+                .collect(),
+            PurgeScope::Prefix(prefix) => self
+                .backend
+                .keys()
+                .into_iter()
+                .filter(|key| target_url_matches(key, &|url| url.starts_with(prefix.as_str())))
+                .collect(),
+            PurgeScope::Tag(tag) => self
+                .backend
+                .entries()
+                .into_iter()
+                .filter(|(_, entry)| surrogate_keys(entry.response.headers()).contains(tag))
+                .map(|(key, _)| key)
+                .collect(),
+        };
+
+        keys.iter().filter(|key| self.remove(key)).count()
+    }
+    /// Remove an entry if it is past its expiry and retention, returns true if removed
+    pub fn remove_expired(&self, key: &str, now: DateTime<Utc>) -> bool {
+        let is_removed = match self.backend.get(key) {
+            Some(entry) if !entry.is_retained(now) => self.backend.remove(key),
+            _ => false,
+        };
+        if is_removed {
+            self.counters.record(CacheEvent::EvictionTtl);
+        }
+
+        is_removed
+    }
+    /// Remove the entries that are no longer retained, returns the amount removed
+    pub fn purge_expired(&self, now: DateTime<Utc>) -> usize {
+        let amt_purged = self.backend.purge_expired(now);
+        self.counters
+            .record_n(CacheEvent::EvictionTtl, amt_purged as u64);
+
+        amt_purged
+    }
+    /// Copy of every entry with its key
+    pub fn entries(&self) -> Vec<(String, Arc<CachedEntry>)> {
+        self.backend.entries()
+    }
+    /// Amount of entries in the backend
+    pub fn len(&self) -> usize {
+        self.backend.stats().entries
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Total size of the entries, in bytes
+    pub fn size_bytes(&self) -> u64 {
+        self.backend.stats().size_bytes
+    }
+    /// Amount of entries evicted to make room for new ones since the cache was created
+    pub fn evictions(&self) -> u64 {
+        self.backend.stats().evictions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache_utils::{
+        eviction::LfuOrder,
+        test_utils::{entry_for, response},
+    };
+
+    fn entry(body: &'static [u8]) -> Arc<CachedEntry> {
+        Arc::new(entry_for(
+            response(body, &[("cache-control", "max-age=60")]),
+            Utc::now(),
+        ))
+    }
+
+    /// Shard holding `a`, `b` and `c`, full. `a` and `b` are read more often than `c`
+    fn full_shard() -> Cache {
+        let entry_bytes = entry(b"a").size_bytes;
+        let mut shard = Cache::new(3 * entry_bytes, Box::new(LfuOrder::default()));
+        for key in ["a", "b", "c"] {
+            assert!(shard.insert(key.to_string(), entry(b"a")));
         }
+        for key in ["a", "b", "a", "b"] {
+            shard.get(key);
+        }
+
+        shard
+    }
+
+    #[test]
+    fn rejected_entry_evicts_nothing() {
+        let mut shard = full_shard();
+
+        // `c` would not free enough room, then `d` itself is the least frequent
+        assert!(!shard.insert("d".to_string(), entry(b"dd")));
+        assert_eq!(shard.len(), 3);
+        assert!(shard.peek("c").is_some());
+        assert_eq!(shard.evictions(), 0);
+
+        // `c` is still tracked, and evicted for an entry that fits in its place
+        assert!(shard.insert("e".to_string(), entry(b"e")));
+        assert!(shard.peek("c").is_none());
+        assert_eq!(shard.evictions(), 1);
+    }
+
+    #[test]
+    fn rejected_replacement_keeps_the_existing_entry() {
+        let mut shard = full_shard();
+        let size_bytes = shard.size_bytes();
+
+        // the new `c` does not fit next to `a` and `b`, and `c` is the least frequent
+        assert!(!shard.insert("c".to_string(), entry(b"cc")));
+        assert_eq!(shard.peek("c").unwrap().response.body().as_ref(), b"a");
+        assert_eq!(shard.size_bytes(), size_bytes);
+        assert_eq!(shard.evictions(), 0);
     }
 }
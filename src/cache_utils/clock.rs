@@ -0,0 +1,98 @@
+// imports
+use chrono::{DateTime, TimeZone, Utc};
+use std::{
+    fmt::Debug,
+    sync::atomic::{AtomicI64, Ordering},
+};
+
+/// Source of the current time for freshness, staleness and expiry decisions
+///
+/// `HTTPCache` holds one (see `HTTPCache::with_clock`), the proxy and the ttl sweeper read the time from it
+pub trait Clock: Send + Sync + Debug {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+/// Wall clock, used unless another clock is injected
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+#[derive(Debug)]
+/// Clock that only moves when advanced, so expiry can be checked without sleeping
+///
+/// i.e. insert an entry fresh for 30s, `advance(Duration::seconds(31))`, and it is stale
+pub struct MockClock {
+    /// Current time, as unix timestamp in millis
+    now_ms: AtomicI64,
+}
+
+impl MockClock {
+    /// Create a clock stopped at `start`
+    pub fn new(start: DateTime<Utc>) -> Self {
+        Self {
+            now_ms: AtomicI64::new(start.timestamp_millis()),
+        }
+    }
+    /// Move the clock forward (or backward, for a negative duration), saturating at the ends of `i64` millis
+    pub fn advance(&self, by: chrono::Duration) {
+        let by_ms = by.num_milliseconds();
+        let _ = self
+            .now_ms
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |now_ms| {
+                Some(now_ms.saturating_add(by_ms))
+            });
+    }
+    /// Move the clock to `now`
+    pub fn set(&self, now: DateTime<Utc>) {
+        self.now_ms.store(now.timestamp_millis(), Ordering::SeqCst);
+    }
+}
+
+impl Default for MockClock {
+    /// Stopped at the current wall clock time
+    fn default() -> Self {
+        Self::new(Utc::now())
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> DateTime<Utc> {
+        let now_ms = self.now_ms.load(Ordering::SeqCst);
+
+        // advanced past the range of `DateTime<Utc>`
+        Utc.timestamp_millis_opt(now_ms)
+            .single()
+            .unwrap_or(if now_ms < 0 {
+                DateTime::<Utc>::MIN_UTC
+            } else {
+                DateTime::<Utc>::MAX_UTC
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_clock_saturates() {
+        let clock = MockClock::new(DateTime::<Utc>::MAX_UTC);
+        clock.advance(chrono::Duration::days(1));
+        assert_eq!(clock.now(), DateTime::<Utc>::MAX_UTC);
+
+        // millis do not wrap around to the past
+        clock.advance(chrono::Duration::MAX);
+        clock.advance(chrono::Duration::MAX);
+        assert_eq!(clock.now(), DateTime::<Utc>::MAX_UTC);
+
+        clock.set(DateTime::<Utc>::MIN_UTC);
+        clock.advance(chrono::Duration::MIN);
+        clock.advance(chrono::Duration::MIN);
+        assert_eq!(clock.now(), DateTime::<Utc>::MIN_UTC);
+    }
+}
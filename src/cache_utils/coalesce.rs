@@ -0,0 +1,285 @@
+// imports
+use std::{
+    collections::HashMap,
+    sync::{Arc, Condvar, Mutex},
+};
+// local
+use super::cache::MapValue;
+use crate::http_utils::errors::Result;
+
+/// Response of a revalidation, and whether origin answered it with `304 Not Modified`
+pub type Revalidation = (MapValue, bool);
+
+/// Outcome of an origin fetch, shared between every request waiting on it.
+/// `failure::Error` is not `Clone`, so errors are shared as their message
+type SharedResult<T> = std::result::Result<Arc<T>, String>;
+
+#[derive(Debug)]
+/// A fetch in progress for a single key
+struct InFlight<T> {
+    result: Mutex<Option<SharedResult<T>>>,
+    done: Condvar,
+}
+
+impl<T> Default for InFlight<T> {
+    fn default() -> Self {
+        Self {
+            result: Mutex::new(None),
+            done: Condvar::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+/// Single-flight for origin fetches: cache misses, or revalidations of stale entries.
+///
+/// The first request that misses on a key (the leader) fetches from origin,
+/// concurrent requests on the same key wait for and share the leader's result.
+pub struct RequestCoalescer<T = MapValue> {
+    in_flight: Mutex<HashMap<String, Arc<InFlight<T>>>>,
+}
+
+impl<T> Default for RequestCoalescer<T> {
+    fn default() -> Self {
+        Self {
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+/// Completes the fetch when the leader is done (or unwinds), so waiters are never stuck
+struct LeaderGuard<'a, T> {
+    coalescer: &'a RequestCoalescer<T>,
+    key: &'a str,
+    in_flight: Arc<InFlight<T>>,
+}
+
+impl<T> LeaderGuard<'_, T> {
+    fn complete(&self, shared_result: SharedResult<T>) {
+        // remove first, so requests arriving from now on start a new fetch
+        let mut in_flight_map = self
+            .coalescer
+            .in_flight
+            .lock()
+            .expect("Poisoned mutex: in-flight requests");
+        // only remove our own fetch, a newer one may already be running for the key
+        if let Some(current) = in_flight_map.get(self.key) {
+            if Arc::ptr_eq(current, &self.in_flight) {
+                in_flight_map.remove(self.key);
+            }
+        }
+        drop(in_flight_map);
+
+        let mut result = self
+            .in_flight
+            .result
+            .lock()
+            .expect("Poisoned mutex: in-flight result");
+        if result.is_none() {
+            *result = Some(shared_result);
+        }
+        self.in_flight.done.notify_all();
+    }
+}
+
+impl<T> Drop for LeaderGuard<'_, T> {
+    fn drop(&mut self) {
+        self.complete(Err(String::from("In-flight request was aborted")));
+    }
+}
+
+impl<T> RequestCoalescer<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// A fetch for `key` is currently running
+    pub fn is_in_flight(&self, key: &str) -> bool {
+        self.in_flight
+            .lock()
+            .expect("Poisoned mutex: in-flight requests")
+            .contains_key(key)
+    }
+    /// Run `fetch` for `key`, unless a fetch for the same key is already in flight,
+    /// in which case wait for it and return its response (or its error)
+    pub fn fetch<F>(&self, key: &str, fetch: F) -> Result<Arc<T>>
+    where
+        F: FnOnce() -> Result<T>,
+    {
+        let (in_flight, is_leader) = {
+            let mut in_flight_map = self
+                .in_flight
+                .lock()
+                .expect("Poisoned mutex: in-flight requests");
+            match in_flight_map.get(key) {
+                Some(in_flight) => (Arc::clone(in_flight), false),
+                None => {
+                    let in_flight = Arc::new(InFlight::default());
+                    in_flight_map.insert(key.to_string(), Arc::clone(&in_flight));
+                    (in_flight, true)
+                }
+            }
+        };
+
+        if !is_leader {
+            println!("request already in flight... waiting on it: {key}");
+            return Self::wait(&in_flight);
+        }
+
+        let guard = LeaderGuard {
+            coalescer: self,
+            key,
+            in_flight,
+        };
+        let result = fetch().map(Arc::new);
+        guard.complete(match &result {
+            Ok(res) => Ok(Arc::clone(res)),
+            Err(err) => Err(err.to_string()),
+        });
+
+        result
+    }
+    /// Block until the leader completes the fetch
+    fn wait(in_flight: &InFlight<T>) -> Result<Arc<T>> {
+        let mut result = in_flight
+            .result
+            .lock()
+            .expect("Poisoned mutex: in-flight result");
+        while result.is_none() {
+            result = in_flight
+                .done
+                .wait(result)
+                .expect("Poisoned mutex: in-flight result");
+        }
+
+        match result.as_ref().expect("In-flight result is set when done") {
+            Ok(res) => Ok(Arc::clone(res)),
+            Err(msg) => Err(failure::err_msg(msg.clone())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_utils::errors::fmt_error;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    const KEY: &str = "GET http://example.com/";
+
+    fn response(body: &'static [u8]) -> MapValue {
+        MapValue::new(body.into())
+    }
+
+    /// Run `leader` on a thread, and `amt_waiters` fetches of the same key once it is in flight
+    ///
+    /// `leader` runs until all the waiters are about to fetch. Returns their results
+    fn fetch_concurrently<F>(
+        coalescer: &Arc<RequestCoalescer>,
+        amt_waiters: usize,
+        upstream_calls: &Arc<AtomicUsize>,
+        leader: F,
+    ) -> Vec<Result<Arc<MapValue>>>
+    where
+        F: FnOnce() -> Result<MapValue> + Send + 'static,
+    {
+        let entered = Arc::new(AtomicUsize::new(0));
+        let (started_tx, started_rx) = mpsc::channel();
+        let leader_thread = {
+            let coalescer = Arc::clone(coalescer);
+            let entered = Arc::clone(&entered);
+            let upstream_calls = Arc::clone(upstream_calls);
+            thread::spawn(move || {
+                coalescer.fetch(KEY, || {
+                    upstream_calls.fetch_add(1, Ordering::SeqCst);
+                    started_tx.send(()).unwrap();
+                    while entered.load(Ordering::SeqCst) < amt_waiters {
+                        thread::yield_now();
+                    }
+                    // let the last waiters reach the condvar
+                    thread::sleep(Duration::from_millis(50));
+                    leader()
+                })
+            })
+        };
+        started_rx.recv().unwrap();
+
+        let waiters: Vec<_> = (0..amt_waiters)
+            .map(|_| {
+                let coalescer = Arc::clone(coalescer);
+                let entered = Arc::clone(&entered);
+                let upstream_calls = Arc::clone(upstream_calls);
+                thread::spawn(move || {
+                    entered.fetch_add(1, Ordering::SeqCst);
+                    coalescer.fetch(KEY, || {
+                        upstream_calls.fetch_add(1, Ordering::SeqCst);
+                        Ok(response(b"late"))
+                    })
+                })
+            })
+            .collect();
+        // the leader's own result is not compared, it may have panicked
+        leader_thread.join().ok();
+
+        waiters
+            .into_iter()
+            .map(|waiter| waiter.join().expect("waiter does not panic"))
+            .collect()
+    }
+
+    #[test]
+    fn concurrent_fetches_share_one_upstream_call() {
+        let coalescer = Arc::new(RequestCoalescer::new());
+        let upstream_calls = Arc::new(AtomicUsize::new(0));
+        let results =
+            fetch_concurrently(&coalescer, 8, &upstream_calls, || Ok(response(b"origin")));
+
+        assert_eq!(upstream_calls.load(Ordering::SeqCst), 1);
+        for result in results {
+            assert_eq!(&result.unwrap().body()[..], b"origin");
+        }
+        assert!(coalescer.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn waiters_see_the_leader_error() {
+        let coalescer = Arc::new(RequestCoalescer::new());
+        let upstream_calls = Arc::new(AtomicUsize::new(0));
+        let results = fetch_concurrently(&coalescer, 4, &upstream_calls, || {
+            Err(fmt_error("connection refused", "Origin"))
+        });
+
+        assert_eq!(upstream_calls.load(Ordering::SeqCst), 1);
+        for result in results {
+            let e = result.expect_err("leader failed");
+            assert!(e.to_string().contains("connection refused"), "{e}");
+        }
+
+        // a failed fetch is not cached, the next one goes upstream again
+        let res = coalescer.fetch(KEY, || Ok(response(b"retry"))).unwrap();
+        assert_eq!(&res.body()[..], b"retry");
+    }
+
+    #[test]
+    fn leader_panic_does_not_leave_waiters_stuck() {
+        let coalescer = Arc::new(RequestCoalescer::new());
+        let upstream_calls = Arc::new(AtomicUsize::new(0));
+        let results =
+            fetch_concurrently(&coalescer, 4, &upstream_calls, || panic!("leader panicked"));
+
+        for result in results {
+            let e = result.expect_err("leader panicked");
+            assert!(e.to_string().contains("aborted"), "{e}");
+        }
+        assert!(coalescer.in_flight.lock().unwrap().is_empty());
+
+        let res = coalescer.fetch(KEY, || Ok(response(b"retry"))).unwrap();
+        assert_eq!(&res.body()[..], b"retry");
+    }
+}
@@ -0,0 +1,198 @@
+// imports
+use bytes::Bytes;
+use flate2::{read::GzDecoder, write::GzEncoder};
+use http::{HeaderMap, HeaderValue};
+use std::io::{Read, Write};
+// local
+use super::{cache::MapValue, config::CompressionKind};
+use crate::http_utils::{
+    constants::{
+        COMPRESSION_GZIP_LEVEL, COMPRESSION_MIN_BYTES, COMPRESSION_ZSTD_LEVEL, SIZE_MAX_BODY,
+    },
+    errors::{fmt_error, ResponseError, Result},
+    response::clone_response,
+};
+
+/// Compress a body with `kind`
+pub fn compress(kind: CompressionKind, body: &[u8]) -> Result<Vec<u8>> {
+    match kind {
+        CompressionKind::Gzip => {
+            let level = flate2::Compression::new(COMPRESSION_GZIP_LEVEL);
+            let mut encoder = GzEncoder::new(Vec::new(), level);
+            encoder.write_all(body)?;
+            Ok(encoder.finish()?)
+        }
+        CompressionKind::Zstd => Ok(zstd::encode_all(body, COMPRESSION_ZSTD_LEVEL)?),
+    }
+}
+
+/// Decompress a body encoded with `kind`, up to `SIZE_MAX_BODY` bytes
+pub fn decompress(kind: CompressionKind, body: &[u8]) -> Result<Vec<u8>> {
+    let decoder: Box<dyn Read + '_> = match kind {
+        CompressionKind::Gzip => Box::new(GzDecoder::new(body)),
+        CompressionKind::Zstd => Box::new(zstd::Decoder::new(body)?),
+    };
+
+    // a corrupt or malicious body must not make us allocate an arbitrary amount
+    let mut decoded = Vec::new();
+    decoder
+        .take(SIZE_MAX_BODY as u64 + 1)
+        .read_to_end(&mut decoded)?;
+    if decoded.len() > SIZE_MAX_BODY {
+        return Err(fmt_error(
+            ResponseError::ResponseBodyTooLarge,
+            "Decompressing body",
+        ));
+    }
+
+    Ok(decoded)
+}
+
+/// Encoding of a response's body, if it is one the proxy can decode
+pub fn content_encoding(res_headers: &HeaderMap) -> Option<CompressionKind> {
+    let value = res_headers.get("content-encoding")?.to_str().ok()?;
+
+    CompressionKind::parse(value.trim()).ok()
+}
+
+/// Client's `accept-encoding` allows `kind` (or `*`), with a non-zero `q`
+///
+/// Without `accept-encoding` only the identity encoding is assumed, i.e. `curl` without `--compressed`
+pub fn accepts_encoding(req_headers: &HeaderMap, kind: CompressionKind) -> bool {
+    req_headers
+        .get_all("accept-encoding")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            let name = params.next().unwrap_or_default();
+            let q = params
+                .find_map(|param| param.strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            (name.eq_ignore_ascii_case(kind.as_str()) || name == "*") && q > 0.0
+        })
+}
+
+/// Copy of a response with its body compressed, `content-encoding` and `content-length` set
+///
+/// None if the response is already encoded, too small to be worth it, or would not get smaller
+pub fn compress_response(res: &MapValue, kind: CompressionKind) -> Option<MapValue> {
+    if res.headers().contains_key("content-encoding") || res.body().len() < COMPRESSION_MIN_BYTES {
+        return None;
+    }
+    let compressed = match compress(kind, res.body()) {
+        Ok(compressed) if compressed.len() < res.body().len() => compressed,
+        Ok(_) => return None,
+        Err(e) => {
+            eprintln!("compressing body failed... storing it uncompressed: {e}");
+            return None;
+        }
+    };
+
+    let compressed_len = compressed.len();
+    let mut compressed_res = clone_response(res).map(|_| Bytes::from(compressed));
+    let headers = compressed_res.headers_mut();
+    headers.insert("content-encoding", HeaderValue::from_static(kind.as_str()));
+    headers.insert("content-length", HeaderValue::from(compressed_len));
+
+    Some(compressed_res)
+}
+
+/// Copy of a response decompressed for a client that does not accept its encoding,
+/// `content-encoding` removed and `content-length` set
+///
+/// None if the client can be sent the response as is
+pub fn decode_for_client(req_headers: &HeaderMap, res: &MapValue) -> Result<Option<MapValue>> {
+    let kind = match content_encoding(res.headers()) {
+        Some(kind) if !accepts_encoding(req_headers, kind) => kind,
+        _ => return Ok(None),
+    };
+    let decoded = decompress(kind, res.body())?;
+
+    let decoded_len = decoded.len();
+    let mut decoded_res = clone_response(res).map(|_| Bytes::from(decoded));
+    let headers = decoded_res.headers_mut();
+    headers.remove("content-encoding");
+    headers.insert("content-length", HeaderValue::from(decoded_len));
+
+    Ok(Some(decoded_res))
+}
+
+/// Etag of a body compressed by the proxy with `kind`: `etag` suffixed with the encoding, i.e. `"v1-gzip"`
+///
+/// A strong etag names exact bytes, the compressed body must not be sent under the uncompressed one's
+pub fn encoded_etag(etag: &HeaderValue, kind: CompressionKind) -> HeaderValue {
+    let suffix = format!("-{}", kind.as_str());
+    let encoded = match etag.as_bytes().strip_suffix(b"\"") {
+        Some(opaque) => [opaque, suffix.as_bytes(), b"\""].concat(),
+        None => [etag.as_bytes(), suffix.as_bytes()].concat(),
+    };
+
+    HeaderValue::from_bytes(&encoded).expect("Suffixed etag is a valid header value")
+}
+
+/// Etag of the body a client is sent, `etag` being the one of the uncompressed body
+///
+/// Bodies compressed by the proxy (`compressed`) get their own etag when sent as is, see `encoded_etag`
+pub fn etag_for_client(
+    req_headers: &HeaderMap,
+    res: &MapValue,
+    etag: &HeaderValue,
+    compressed: bool,
+) -> HeaderValue {
+    match content_encoding(res.headers()) {
+        Some(kind) if compressed && accepts_encoding(req_headers, kind) => encoded_etag(etag, kind),
+        _ => etag.clone(),
+    }
+}
+
+/// Add `accept-encoding` to `vary`, the body sent depends on it
+pub fn vary_on_accept_encoding(res_headers: &mut HeaderMap) {
+    let already_varies = res_headers
+        .get_all("vary")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|name| name.trim().eq_ignore_ascii_case("accept-encoding"));
+    if !already_varies {
+        res_headers.append("vary", HeaderValue::from_static("accept-encoding"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache_utils::test_utils::response;
+
+    #[test]
+    fn encoded_etag_is_suffixed() {
+        let etag = HeaderValue::from_static("\"v1\"");
+        assert_eq!(encoded_etag(&etag, CompressionKind::Gzip), "\"v1-gzip\"");
+        let weak = HeaderValue::from_static("W/\"v1\"");
+        assert_eq!(encoded_etag(&weak, CompressionKind::Zstd), "W/\"v1-zstd\"");
+    }
+
+    #[test]
+    fn compressed_bodies_sent_as_is_get_their_own_etag() {
+        let etag = HeaderValue::from_static("\"v1\"");
+        let res = response(&[b'a'; 4096], &[("etag", "\"v1\"")]);
+        let compressed = compress_response(&res, CompressionKind::Gzip).unwrap();
+
+        let mut gzip = HeaderMap::new();
+        gzip.insert("accept-encoding", HeaderValue::from_static("gzip"));
+        assert_eq!(
+            etag_for_client(&gzip, &compressed, &etag, true),
+            "\"v1-gzip\""
+        );
+        // decompressed for the client: the uncompressed body's etag
+        assert_eq!(
+            etag_for_client(&HeaderMap::new(), &compressed, &etag, true),
+            etag
+        );
+        // encoded by the upstream, its etag already names the encoded body
+        assert_eq!(etag_for_client(&gzip, &compressed, &etag, false), etag);
+    }
+}
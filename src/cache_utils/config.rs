@@ -0,0 +1,472 @@
+// imports
+use std::{path::PathBuf, time::Duration};
+// local
+use crate::http_utils::{
+    constants::*,
+    errors::{fmt_error, Result},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// How long stale entries may be served on a route (RFC 5861), in seconds
+///
+/// Upstream `stale-while-revalidate`/`stale-if-error` directives are used when present,
+/// otherwise the route's defaults. Both are capped by `max_staleness_sec`.
+pub struct StalePolicy {
+    pub stale_while_revalidate_sec: Option<i64>,
+    pub stale_if_error_sec: Option<i64>,
+    pub max_staleness_sec: i64,
+}
+
+impl Default for StalePolicy {
+    /// Only honor upstream directives
+    fn default() -> Self {
+        Self {
+            stale_while_revalidate_sec: None,
+            stale_if_error_sec: None,
+            max_staleness_sec: CACHE_MAX_STALENESS_SEC,
+        }
+    }
+}
+
+impl StalePolicy {
+    /// Parse a policy from a flag value: `<stale-while-revalidate>:<stale-if-error>:<max-staleness>`
+    pub fn parse(value: &str) -> Result<Self> {
+        let parts: Vec<&str> = value.split(':').collect();
+        if parts.len() != 3 {
+            return Err(fmt_error(
+                value,
+                "Invalid stale policy, expected <swr>:<sie>:<max>",
+            ));
+        }
+
+        Ok(Self {
+            stale_while_revalidate_sec: Some(parse_flag("stale-while-revalidate", parts[0])?),
+            stale_if_error_sec: Some(parse_flag("stale-if-error", parts[1])?),
+            max_staleness_sec: parse_flag("max-staleness", parts[2])?,
+        })
+    }
+    /// Stale-while-revalidate window given the upstream directive, in seconds
+    pub fn stale_while_revalidate(&self, upstream_sec: Option<i64>) -> i64 {
+        self.window(upstream_sec, self.stale_while_revalidate_sec)
+    }
+    /// Stale-if-error window given the upstream directive, in seconds
+    pub fn stale_if_error(&self, upstream_sec: Option<i64>) -> i64 {
+        self.window(upstream_sec, self.stale_if_error_sec)
+    }
+    fn window(&self, upstream_sec: Option<i64>, default_sec: Option<i64>) -> i64 {
+        upstream_sec
+            .or(default_sec)
+            .unwrap_or(0)
+            .clamp(0, self.max_staleness_sec.max(0))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Where cached entries are stored, see `HTTPCache::open`
+pub enum BackendKind {
+    Memory,
+    Disk,
+    Redis,
+}
+
+impl BackendKind {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "memory" => Ok(Self::Memory),
+            "disk" => Ok(Self::Disk),
+            "redis" => Ok(Self::Redis),
+            _ => Err(fmt_error(
+                value,
+                "Invalid backend, expected memory|disk|redis",
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Which entries the memory backend evicts when over its byte budget, see `EvictionPolicy`
+pub enum EvictionKind {
+    Lru,
+    Lfu,
+    /// Window TinyLFU
+    TinyLfu,
+}
+
+impl EvictionKind {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "lru" => Ok(Self::Lru),
+            "lfu" => Ok(Self::Lfu),
+            "tinylfu" => Ok(Self::TinyLfu),
+            _ => Err(fmt_error(
+                value,
+                "Invalid eviction policy, expected lru|lfu|tinylfu",
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Encoding cached bodies are stored with, see `CachedEntry::with_compression`
+pub enum CompressionKind {
+    Gzip,
+    Zstd,
+}
+
+impl CompressionKind {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "gzip" => Ok(Self::Gzip),
+            "zstd" => Ok(Self::Zstd),
+            _ => Err(fmt_error(
+                value,
+                "Invalid compression, expected none|gzip|zstd",
+            )),
+        }
+    }
+    /// `content-encoding` value
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+/// Runtime configuration for the proxy cache
+///
+/// Defaults come from `constants.rs`, and can be overridden with command line flags
+pub struct CacheConfig {
+    /// Where cached entries are stored
+    pub backend: BackendKind,
+    /// Address of the Redis-protocol server, for the redis backend
+    pub redis_addr: String,
+    /// Byte budget for all cached headers and bodies
+    pub max_bytes: usize,
+    /// Responses bigger than this are not cached, in bytes
+    pub max_object_bytes: usize,
+    /// Eviction policy of the memory backend
+    pub eviction: EvictionKind,
+    /// Replay requests on every eviction policy, to compare their hit ratios in stats
+    pub eviction_compare: bool,
+    /// Encoding cached bodies are stored with, stored as received if None
+    pub compression: Option<CompressionKind>,
+    /// Error statuses from origin that are cached (negative caching)
+    pub negative_statuses: Vec<u16>,
+    /// Max freshness lifetime of cached error responses, in seconds
+    pub negative_ttl_sec: i64,
+    /// Directory of the disk backend, in front of memory unless `backend` is `disk`. Disabled if None
+    pub disk_dir: Option<PathBuf>,
+    /// Byte budget for the disk tier
+    pub disk_max_bytes: u64,
+    /// Bearer token required by `PURGE` requests, purging is disabled if None
+    pub purge_token: Option<String>,
+    /// Snapshot loaded into the cache on startup
+    pub snapshot_load: Option<PathBuf>,
+    /// Snapshot written on `SIGUSR1` and on shutdown
+    pub snapshot_save: Option<PathBuf>,
+    /// File of target urls prefetched on startup
+    pub warm_urls: Option<PathBuf>,
+    /// Max concurrent origin requests when warming
+    pub warm_concurrency: usize,
+    /// How often the background sweeper purges expired entries
+    pub sweep_interval: Duration,
+    /// Stale policies by target url prefix, the longest matching prefix wins
+    pub stale_routes: Vec<(String, StalePolicy)>,
+    /// Stale policy for targets not matching any route
+    pub default_stale_policy: StalePolicy,
+    /// Max share of the freshness lifetime randomly taken off each entry's expiry, disabled if 0
+    pub expiry_jitter_percent: u64,
+    /// Hits after which an entry is refreshed ahead of its expiry
+    pub refresh_ahead_min_hits: u64,
+    /// Share of the freshness lifetime left when hot entries are refreshed, disabled if 0
+    pub refresh_ahead_percent: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            backend: BackendKind::Memory,
+            redis_addr: CACHE_REDIS_ADDR.to_string(),
+            max_bytes: CACHE_MAX_BYTES,
+            max_object_bytes: CACHE_MAX_OBJECT_BYTES,
+            eviction: EvictionKind::Lru,
+            eviction_compare: false,
+            compression: None,
+            negative_statuses: CACHE_NEGATIVE_STATUSES.to_vec(),
+            negative_ttl_sec: CACHE_NEGATIVE_TTL_SEC,
+            disk_dir: None,
+            disk_max_bytes: CACHE_DISK_MAX_BYTES,
+            purge_token: None,
+            snapshot_load: None,
+            snapshot_save: None,
+            warm_urls: None,
+            warm_concurrency: CACHE_WARM_CONCURRENCY,
+            sweep_interval: Duration::from_secs(CACHE_SWEEP_INTERVAL_SEC),
+            stale_routes: Vec::new(),
+            default_stale_policy: StalePolicy::default(),
+            expiry_jitter_percent: CACHE_EXPIRY_JITTER_PERCENT,
+            refresh_ahead_min_hits: CACHE_REFRESH_AHEAD_MIN_HITS,
+            refresh_ahead_percent: CACHE_REFRESH_AHEAD_PERCENT,
+        }
+    }
+}
+
+impl CacheConfig {
+    /// Build the config from command line flags (i.e. `--sweep-interval-sec 5`)
+    ///
+    /// The first argument (program name) is skipped
+    pub fn from_args<I>(args: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut config = Self::default();
+        let mut args = args.into_iter().skip(1);
+
+        while let Some(flag) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| fmt_error(&flag, "Missing value for flag"))?;
+
+            match flag.as_str() {
+                "--backend" => config.backend = BackendKind::parse(&value)?,
+                "--redis-addr" => config.redis_addr = value,
+                "--max-bytes" => config.max_bytes = parse_flag(&flag, &value)?,
+                "--max-object-bytes" => config.max_object_bytes = parse_flag(&flag, &value)?,
+                "--eviction" => config.eviction = EvictionKind::parse(&value)?,
+                "--eviction-compare" => config.eviction_compare = parse_flag(&flag, &value)?,
+                "--compression" => {
+                    config.compression = match value.as_str() {
+                        "none" => None,
+                        _ => Some(CompressionKind::parse(&value)?),
+                    }
+                }
+                // i.e. `--negative-statuses 404,410,503`, empty to disable
+                "--negative-statuses" => {
+                    config.negative_statuses = value
+                        .split(',')
+                        .filter(|status| !status.trim().is_empty())
+                        .map(|status| parse_flag(&flag, status.trim()))
+                        .collect::<Result<_>>()?
+                }
+                "--negative-ttl-sec" => config.negative_ttl_sec = parse_flag(&flag, &value)?,
+                "--disk-dir" => config.disk_dir = Some(PathBuf::from(value)),
+                "--disk-max-bytes" => config.disk_max_bytes = parse_flag(&flag, &value)?,
+                "--purge-token" => config.purge_token = Some(value),
+                "--snapshot-load" => config.snapshot_load = Some(PathBuf::from(value)),
+                "--snapshot-save" => config.snapshot_save = Some(PathBuf::from(value)),
+                "--warm-urls" => config.warm_urls = Some(PathBuf::from(value)),
+                "--warm-concurrency" => config.warm_concurrency = parse_flag(&flag, &value)?,
+                "--sweep-interval-sec" => {
+                    config.sweep_interval = Duration::from_secs(parse_flag(&flag, &value)?);
+                    // the sweeper would spin without sleeping
+                    if config.sweep_interval.is_zero() {
+                        return Err(fmt_error(
+                            &value,
+                            "Invalid sweep interval, expected 1 or more",
+                        ));
+                    }
+                }
+                // i.e. `--stale-route https://blockstream.info/api/blocks=5:60:300`
+                "--stale-route" => {
+                    let (prefix, policy) = value
+                        .rsplit_once('=')
+                        .ok_or_else(|| fmt_error(&value, "Invalid stale route"))?;
+                    config
+                        .stale_routes
+                        .push((prefix.to_string(), StalePolicy::parse(policy)?));
+                }
+                "--stale-default" => config.default_stale_policy = StalePolicy::parse(&value)?,
+                "--expiry-jitter-percent" => {
+                    config.expiry_jitter_percent = parse_flag(&flag, &value)?;
+                    if config.expiry_jitter_percent > 100 {
+                        return Err(fmt_error(&value, "Invalid expiry jitter, expected 0-100"));
+                    }
+                }
+                "--refresh-ahead-min-hits" => {
+                    config.refresh_ahead_min_hits = parse_flag(&flag, &value)?
+                }
+                "--refresh-ahead-percent" => {
+                    config.refresh_ahead_percent = parse_flag(&flag, &value)?;
+                    if config.refresh_ahead_percent > 100 {
+                        return Err(fmt_error(
+                            &value,
+                            "Invalid refresh-ahead share, expected 0-100",
+                        ));
+                    }
+                }
+                _ => return Err(fmt_error(&flag, "Unknown flag")),
+            }
+        }
+        // the memory budget is split across shards, a cacheable response must fit in one.
+        // Also with `--disk-dir`, the memory tier in front of the disk is sharded the same way
+        if config.backend == BackendKind::Memory
+            && config.max_object_bytes > config.shard_max_bytes()
+        {
+            return Err(fmt_error(
+                format!(
+                    "{} > {} / {CACHE_SHARDS}",
+                    config.max_object_bytes, config.max_bytes
+                ),
+                "--max-object-bytes must be at most --max-bytes / amount of shards",
+            ));
+        }
+
+        Ok(config)
+    }
+    /// Error status from origin that is cached
+    pub fn is_negative_status(&self, status: u16) -> bool {
+        self.negative_statuses.contains(&status)
+    }
+    /// Byte budget of each memory shard, see `MemoryBackend::new`
+    pub fn shard_max_bytes(&self) -> usize {
+        self.max_bytes.div_ceil(CACHE_SHARDS)
+    }
+    /// Random jitter for the expiry of an entry fresh for `lifetime_sec`, in millis
+    ///
+    /// Up to `expiry_jitter_percent` of the lifetime, taken off the expiry so the upstream's is never exceeded
+    pub fn expiry_jitter_ms(&self, lifetime_sec: i64) -> i64 {
+        let max_jitter_ms = lifetime_sec.max(0) * 1000 * self.expiry_jitter_percent as i64 / 100;
+        if max_jitter_ms == 0 {
+            return 0;
+        }
+
+        -fastrand::i64(0..=max_jitter_ms)
+    }
+    /// Stale policy for a target url
+    pub fn stale_policy(&self, target_url: &str) -> &StalePolicy {
+        self.stale_routes
+            .iter()
+            .filter(|(prefix, _)| target_url.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, policy)| policy)
+            .unwrap_or(&self.default_stale_policy)
+    }
+}
+
+/// Parse the value for a flag, erroring with the flag name
+fn parse_flag<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T> {
+    value
+        .parse::<T>()
+        .map_err(|_| fmt_error(value, &format!("Invalid value for {flag}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(flags: &[&str]) -> Vec<String> {
+        std::iter::once("proxy")
+            .chain(flags.iter().copied())
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn max_object_bytes_must_fit_in_a_shard() {
+        // 1 MiB objects do not fit in 16 shards of 64 KiB
+        assert!(CacheConfig::from_args(args(&["--max-bytes", "1048576"])).is_err());
+
+        let config = CacheConfig::from_args(args(&[
+            "--max-bytes",
+            "1048576",
+            "--max-object-bytes",
+            "65536",
+        ]))
+        .unwrap();
+        assert_eq!(config.shard_max_bytes(), 65536);
+
+        // the memory tier in front of the disk is sharded too
+        assert!(CacheConfig::from_args(args(&[
+            "--max-bytes",
+            "1048576",
+            "--disk-dir",
+            "/tmp/tcp-proxy-cache"
+        ]))
+        .is_err());
+
+        // disk and redis are not split into shards
+        assert!(CacheConfig::from_args(args(&[
+            "--max-bytes",
+            "1048576",
+            "--backend",
+            "disk",
+            "--disk-dir",
+            "/tmp/tcp-proxy-cache"
+        ]))
+        .is_ok());
+        assert!(
+            CacheConfig::from_args(args(&["--max-bytes", "1048576", "--backend", "redis"])).is_ok()
+        );
+    }
+
+    #[test]
+    fn eviction_compare_is_opt_in() {
+        assert!(!CacheConfig::from_args(args(&[])).unwrap().eviction_compare);
+        assert!(
+            CacheConfig::from_args(args(&["--eviction-compare", "true"]))
+                .unwrap()
+                .eviction_compare
+        );
+    }
+
+    #[test]
+    fn sweep_interval_must_not_be_zero() {
+        assert!(CacheConfig::from_args(args(&["--sweep-interval-sec", "0"])).is_err());
+
+        let config = CacheConfig::from_args(args(&["--sweep-interval-sec", "5"])).unwrap();
+        assert_eq!(config.sweep_interval, Duration::from_secs(5));
+    }
+
+    #[test]
+    fn negative_statuses_are_a_list() {
+        let config = CacheConfig::from_args(args(&[])).unwrap();
+        assert!(config.is_negative_status(404));
+        assert!(!config.is_negative_status(500));
+
+        let config = CacheConfig::from_args(args(&[
+            "--negative-statuses",
+            "404, 500",
+            "--negative-ttl-sec",
+            "30",
+        ]))
+        .unwrap();
+        assert_eq!(config.negative_statuses, vec![404, 500]);
+        assert!(config.is_negative_status(500));
+        assert!(!config.is_negative_status(410));
+        assert_eq!(config.negative_ttl_sec, 30);
+
+        // empty disables negative caching
+        let config = CacheConfig::from_args(args(&["--negative-statuses", ""])).unwrap();
+        assert!(config.negative_statuses.is_empty());
+        assert!(!config.is_negative_status(404));
+
+        assert!(CacheConfig::from_args(args(&["--negative-statuses", "404,abc"])).is_err());
+    }
+
+    #[test]
+    fn refresh_ahead_percent_is_a_percentage() {
+        assert!(CacheConfig::from_args(args(&["--refresh-ahead-percent", "101"])).is_err());
+        assert!(CacheConfig::from_args(args(&["--refresh-ahead-percent", "-1"])).is_err());
+
+        let config = CacheConfig::from_args(args(&["--refresh-ahead-percent", "100"])).unwrap();
+        assert_eq!(config.refresh_ahead_percent, 100);
+    }
+
+    #[test]
+    fn expiry_jitter_stays_within_its_share_of_the_lifetime() {
+        let config = CacheConfig::from_args(args(&["--expiry-jitter-percent", "10"])).unwrap();
+        for _ in 0..1000 {
+            let jitter_ms = config.expiry_jitter_ms(60);
+            assert!((-6000..=0).contains(&jitter_ms), "jitter {jitter_ms}ms");
+        }
+        assert_eq!(config.expiry_jitter_ms(0), 0);
+        assert_eq!(config.expiry_jitter_ms(-60), 0);
+
+        let config = CacheConfig::from_args(args(&["--expiry-jitter-percent", "0"])).unwrap();
+        for _ in 0..100 {
+            assert_eq!(config.expiry_jitter_ms(60), 0);
+        }
+    }
+}
@@ -0,0 +1,532 @@
+// imports
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    thread,
+};
+// local
+use super::{
+    backend::{BackendStats, CacheBackend, HitCounters},
+    config::CacheConfig,
+    entry::CachedEntry,
+    key::CacheKey,
+    stored::{read_entry, write_entry, StoredEntry, STORED_ENTRY_VERSION},
+};
+use crate::http_utils::{
+    constants::CACHE_DISK_INDEX_FILE,
+    errors::{fmt_error, Result, StorageError},
+    formatting::fnv1a_hash,
+};
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+/// Index of the disk tier, saved as json next to the entry files
+struct DiskIndex {
+    version: u16,
+    entries: HashMap<String, DiskIndexEntry>,
+    /// Total size of the entry files, kept up to date instead of summed on every write
+    #[serde(skip)]
+    size_bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DiskIndexEntry {
+    file_name: String,
+    size_bytes: u64,
+    /// Entries past this are removed without reading their file
+    retain_until_ms: i64,
+}
+
+impl DiskIndex {
+    /// Remove an entry, keeping `size_bytes` up to date
+    fn remove(&mut self, key: &str) -> Option<DiskIndexEntry> {
+        let removed = self.entries.remove(key)?;
+        self.size_bytes -= removed.size_bytes;
+
+        Some(removed)
+    }
+}
+
+#[derive(Debug)]
+/// Disk backend, one file per entry in a cache directory, and an index
+///
+/// Used alone or as the second tier behind memory (see `TieredBackend`),
+/// so entries evicted from memory and entries cached before a restart can be served from disk.
+/// Files are checked against their checksum when read, corrupt files are removed and skipped.
+/// The index is saved by `flush` (on each purge and on drop) rather than on every write,
+/// files written since it was last saved are read back on `open`.
+pub struct DiskBackend {
+    dir: PathBuf,
+    max_bytes: u64,
+    index: Mutex<DiskIndex>,
+    /// The index changed since it was last saved
+    is_index_dirty: AtomicBool,
+    /// Amount of entries removed to fit in `max_bytes`
+    evictions: AtomicU64,
+    /// Stale policies, applied when entries are read back
+    config: Arc<CacheConfig>,
+    hits: HitCounters,
+}
+
+impl DiskBackend {
+    /// Open the cache directory, creating it if needed
+    ///
+    /// The index is rebuilt from the entry files if it is missing or unreadable,
+    /// entry files missing from it are read back into it
+    pub fn open(dir: &Path, max_bytes: u64, config: Arc<CacheConfig>) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        // left over by writes interrupted by a crash
+        for dir_entry in fs::read_dir(dir)? {
+            let path = dir_entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) == Some("tmp") {
+                fs::remove_file(path).ok();
+            }
+        }
+        let disk = Self {
+            dir: dir.to_path_buf(),
+            max_bytes,
+            index: Mutex::new(DiskIndex::default()),
+            is_index_dirty: AtomicBool::new(false),
+            evictions: AtomicU64::new(0),
+            config,
+            hits: HitCounters::default(),
+        };
+
+        let mut index = match disk.read_index() {
+            Ok(Some(index)) => index,
+            Ok(None) => disk.rebuild_index()?,
+            Err(e) => {
+                eprintln!("disk cache index unreadable ({e})... rebuilding from entry files");
+                disk.rebuild_index()?
+            }
+        };
+        // files written after the index was last saved (i.e. before a crash or a signal) are not in it,
+        // they are read back instead
+        let indexed_files: HashSet<String> = index
+            .entries
+            .values()
+            .map(|e| e.file_name.clone())
+            .collect();
+        for dir_entry in fs::read_dir(dir)? {
+            let path = dir_entry?.path();
+            let is_indexed = path
+                .file_name()
+                .and_then(|file_name| file_name.to_str())
+                .is_some_and(|file_name| indexed_files.contains(file_name));
+            if path.extension().and_then(|ext| ext.to_str()) == Some("entry") && !is_indexed {
+                Self::index_entry_file(&mut index, &path)?;
+            }
+        }
+        index.size_bytes = index.entries.values().map(|e| e.size_bytes).sum();
+        *disk.lock_index() = index;
+        disk.save_index(&disk.lock_index())?;
+
+        Ok(disk)
+    }
+    fn lock_index(&self) -> MutexGuard<'_, DiskIndex> {
+        self.index.lock().expect("Poisoned mutex: disk cache index")
+    }
+    fn index_path(&self) -> PathBuf {
+        self.dir.join(CACHE_DISK_INDEX_FILE)
+    }
+    /// Entry file for a key, named after the hash of the key
+    fn file_name(key: &str) -> String {
+        format!("{:016x}.entry", fnv1a_hash(&[key.as_bytes()]))
+    }
+    /// Read the saved index, None if there is none
+    fn read_index(&self) -> Result<Option<DiskIndex>> {
+        let index_path = self.index_path();
+        if !index_path.exists() {
+            return Ok(None);
+        }
+
+        let index: DiskIndex = serde_json::from_reader(BufReader::new(File::open(index_path)?))?;
+        if index.version != STORED_ENTRY_VERSION {
+            return Err(fmt_error(
+                StorageError::UnsupportedVersion(index.version),
+                "Reading disk cache index",
+            ));
+        }
+
+        Ok(Some(index))
+    }
+    /// Index built by reading every entry file, corrupt files are removed
+    fn rebuild_index(&self) -> Result<DiskIndex> {
+        let mut index = DiskIndex {
+            version: STORED_ENTRY_VERSION,
+            ..Default::default()
+        };
+
+        for dir_entry in fs::read_dir(&self.dir)? {
+            let path = dir_entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) == Some("entry") {
+                Self::index_entry_file(&mut index, &path)?;
+            }
+        }
+
+        Ok(index)
+    }
+    /// Read an entry file and add it to the index, corrupt files are removed
+    fn index_entry_file(index: &mut DiskIndex, path: &Path) -> Result<()> {
+        match read_entry_file(path) {
+            Ok(stored) => {
+                index.entries.insert(
+                    stored.key.clone(),
+                    DiskIndexEntry {
+                        file_name: Self::file_name(&stored.key),
+                        size_bytes: fs::metadata(path)?.len(),
+                        retain_until_ms: stored.retain_until_ms,
+                    },
+                );
+            }
+            Err(e) => {
+                eprintln!("corrupt disk cache entry {path:?} ({e})... skipping");
+                fs::remove_file(path).ok();
+            }
+        }
+
+        Ok(())
+    }
+    /// Write the index to a temporary file and move it in place, so a crash never leaves it half written
+    fn save_index(&self, index: &DiskIndex) -> Result<()> {
+        let tmp_path = self.dir.join(format!("{CACHE_DISK_INDEX_FILE}.tmp"));
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, index)?;
+        writer.flush()?;
+        drop(writer);
+        fs::rename(tmp_path, self.index_path())?;
+
+        Ok(())
+    }
+    /// Save the index if it changed since it was last saved
+    pub fn flush(&self) -> Result<()> {
+        let index = self.lock_index();
+        if self.is_index_dirty.swap(false, Ordering::SeqCst) {
+            if let Err(e) = self.save_index(&index) {
+                self.is_index_dirty.store(true, Ordering::SeqCst);
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+    /// Write an entry, replacing the one stored under the same key
+    ///
+    /// Entries closest to the end of their retention are removed until the disk tier fits in its budget.
+    /// Returns false (nothing written) if the entry is bigger than the whole budget
+    pub fn store(&self, stored: &StoredEntry) -> Result<bool> {
+        let file_name = Self::file_name(&stored.key);
+        // concurrent writes of the same key must not share a temporary file
+        let tmp_path = self
+            .dir
+            .join(format!("{file_name}.{:?}.tmp", thread::current().id()));
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        write_entry(&mut writer, stored)?;
+        writer.flush()?;
+        drop(writer);
+        let size_bytes = fs::metadata(&tmp_path)?.len();
+        if size_bytes > self.max_bytes {
+            fs::remove_file(&tmp_path)?;
+            return Ok(false);
+        }
+
+        let mut index = self.lock_index();
+        fs::rename(&tmp_path, self.dir.join(&file_name))?;
+        // a different key with the same hash had its file replaced
+        let replaced_keys: Vec<String> = index
+            .entries
+            .iter()
+            .filter(|(key, e)| *key != &stored.key && e.file_name == file_name)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in replaced_keys {
+            index.remove(&key);
+        }
+        if let Some(replaced) = index.entries.insert(
+            stored.key.clone(),
+            DiskIndexEntry {
+                file_name,
+                size_bytes,
+                retain_until_ms: stored.retain_until_ms,
+            },
+        ) {
+            index.size_bytes -= replaced.size_bytes;
+        }
+        index.size_bytes += size_bytes;
+
+        while index.size_bytes > self.max_bytes {
+            let oldest_key = index
+                .entries
+                .iter()
+                .min_by_key(|(_, e)| e.retain_until_ms)
+                .map(|(key, _)| key.clone());
+            match oldest_key.and_then(|key| index.remove(&key)) {
+                Some(removed) => {
+                    self.evictions.fetch_add(1, Ordering::Relaxed);
+                    fs::remove_file(self.dir.join(removed.file_name)).ok();
+                }
+                None => break,
+            }
+        }
+        self.is_index_dirty.store(true, Ordering::SeqCst);
+
+        Ok(true)
+    }
+    /// Read the entry stored under a key
+    ///
+    /// A corrupt or missing file is removed from the index, and treated as a miss
+    pub fn load(&self, key: &str) -> Option<StoredEntry> {
+        let index_entry = self.lock_index().entries.get(key).cloned()?;
+
+        match read_entry_file(&self.dir.join(&index_entry.file_name)) {
+            // keys with the same hash share a file
+            Ok(stored) if stored.key == key => Some(stored),
+            Ok(_) => None,
+            Err(e) => {
+                eprintln!("corrupt disk cache entry for {key} ({e})... skipping");
+                self.delete(key);
+                None
+            }
+        }
+    }
+    /// Remove the entry stored under a key, returns true if removed
+    pub fn delete(&self, key: &str) -> bool {
+        let mut index = self.lock_index();
+        let removed = match index.remove(key) {
+            Some(removed) => removed,
+            None => return false,
+        };
+        fs::remove_file(self.dir.join(removed.file_name)).ok();
+        self.is_index_dirty.store(true, Ordering::SeqCst);
+
+        true
+    }
+    /// Remove the entries that are no longer retained at `now`
+    ///
+    /// Returns the amount of entries removed
+    pub fn purge(&self, now: DateTime<Utc>) -> Result<usize> {
+        let mut index = self.lock_index();
+        let expired_keys: Vec<String> = index
+            .entries
+            .iter()
+            .filter(|(_, e)| e.retain_until_ms <= now.timestamp_millis())
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired_keys {
+            if let Some(removed) = index.remove(key) {
+                fs::remove_file(self.dir.join(removed.file_name)).ok();
+            }
+        }
+        if !expired_keys.is_empty() {
+            self.is_index_dirty.store(true, Ordering::SeqCst);
+        }
+        drop(index);
+        // purges run periodically, writes since the last one are saved with it
+        self.flush()?;
+
+        Ok(expired_keys.len())
+    }
+    /// Amount of entries on disk
+    pub fn len(&self) -> usize {
+        self.lock_index().entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Total size of the entry files, in bytes
+    pub fn size_bytes(&self) -> u64 {
+        self.lock_index().size_bytes
+    }
+}
+
+impl Drop for DiskBackend {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            eprintln!("error saving disk cache index: {e}");
+        }
+    }
+}
+
+impl CacheBackend for DiskBackend {
+    fn name(&self) -> &'static str {
+        "disk"
+    }
+    fn get(&self, key: &str) -> Option<Arc<CachedEntry>> {
+        let stored = self.load(key)?;
+        let target_url = CacheKey::parse(key)?.url;
+
+        match stored.into_entry(self.config.stale_policy(&target_url)) {
+            Ok(entry) => Some(Arc::new(self.hits.attach(key, entry))),
+            Err(e) => {
+                eprintln!("invalid disk cache entry for {key} ({e})... skipping");
+                self.delete(key);
+                None
+            }
+        }
+    }
+    fn insert(&self, key: String, entry: Arc<CachedEntry>) -> bool {
+        match self.store(&StoredEntry::from_entry(&key, &entry)) {
+            Ok(is_stored) => is_stored,
+            Err(e) => {
+                eprintln!("error writing entry to disk cache: {e}");
+                false
+            }
+        }
+    }
+    fn remove(&self, key: &str) -> bool {
+        self.hits.remove(key);
+        self.delete(key)
+    }
+    fn keys(&self) -> Vec<String> {
+        self.lock_index().entries.keys().cloned().collect()
+    }
+    fn purge_expired(&self, now: DateTime<Utc>) -> usize {
+        self.hits.purge(now);
+        match self.purge(now) {
+            Ok(amt_purged) => amt_purged,
+            Err(e) => {
+                eprintln!("error purging disk cache: {e}");
+                0
+            }
+        }
+    }
+    fn stats(&self) -> BackendStats {
+        let index = self.lock_index();
+
+        BackendStats {
+            backend: self.name(),
+            entries: index.entries.len(),
+            size_bytes: index.size_bytes,
+            evictions: self.evictions.load(Ordering::Relaxed),
+            lower_tier: None,
+        }
+    }
+}
+
+/// Read the single entry in a file
+fn read_entry_file(path: &Path) -> Result<StoredEntry> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    read_entry(&mut reader)?.ok_or_else(|| fmt_error(StorageError::InvalidEntry, "Empty file"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache_utils::test_utils::{entry_for, response};
+    use chrono::TimeZone;
+
+    fn stored(key: &str, body: &'static [u8], retain_until_ms: i64) -> StoredEntry {
+        let now = Utc.timestamp_millis_opt(1_700_000_000_000).unwrap();
+        let entry = entry_for(response(body, &[("cache-control", "max-age=60")]), now);
+
+        StoredEntry {
+            retain_until_ms,
+            ..StoredEntry::from_entry(key, &entry)
+        }
+    }
+
+    /// Empty directory, removed by the caller
+    fn cache_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("disk-cache-{name}-{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+
+        dir
+    }
+
+    #[test]
+    fn index_is_saved_on_flush_and_drop() {
+        let dir = cache_dir("flush");
+        let disk = DiskBackend::open(&dir, u64::MAX, Arc::default()).unwrap();
+        assert!(disk
+            .store(&stored("GET http://a/", b"a", i64::MAX))
+            .unwrap());
+        assert!(disk
+            .store(&stored("GET http://b/", b"b", i64::MAX))
+            .unwrap());
+        let size_bytes = disk.size_bytes();
+        assert_eq!(disk.read_index().unwrap().unwrap().entries.len(), 0);
+
+        disk.flush().unwrap();
+        assert_eq!(disk.read_index().unwrap().unwrap().entries.len(), 2);
+        assert!(disk.delete("GET http://a/"));
+        drop(disk);
+
+        let disk = DiskBackend::open(&dir, u64::MAX, Arc::default()).unwrap();
+        assert_eq!(disk.len(), 1);
+        assert!(disk.size_bytes() < size_bytes);
+        assert!(disk.load("GET http://b/").is_some());
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn unindexed_files_are_read_back_on_open() {
+        let dir = cache_dir("unindexed");
+        let disk = DiskBackend::open(&dir, u64::MAX, Arc::default()).unwrap();
+        // written, but the process is gone before the index is saved
+        assert!(disk
+            .store(&stored("GET http://a/", b"a", i64::MAX))
+            .unwrap());
+        std::mem::forget(disk);
+        // corrupt files are still removed
+        fs::write(dir.join("0000000000000000.entry"), b"corrupt").unwrap();
+
+        let disk = DiskBackend::open(&dir, u64::MAX, Arc::default()).unwrap();
+        assert_eq!(disk.len(), 1);
+        assert!(disk.size_bytes() > 0);
+        assert_eq!(disk.load("GET http://a/").unwrap().body.as_ref(), b"a");
+        assert!(!dir.join("0000000000000000.entry").exists());
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn entries_closest_to_expiry_are_evicted_past_budget() {
+        let dir = cache_dir("evict");
+        let entry_bytes = {
+            let disk = DiskBackend::open(&dir, u64::MAX, Arc::default()).unwrap();
+            disk.store(&stored("GET http://a/", b"a", 1)).unwrap();
+            disk.size_bytes()
+        };
+        fs::remove_dir_all(&dir).ok();
+
+        let disk = DiskBackend::open(&dir, entry_bytes * 2, Arc::default()).unwrap();
+        disk.store(&stored("GET http://a/", b"a", 3)).unwrap();
+        disk.store(&stored("GET http://b/", b"b", 1)).unwrap();
+        disk.store(&stored("GET http://c/", b"c", 2)).unwrap();
+        assert_eq!(disk.len(), 2);
+        assert_eq!(disk.size_bytes(), entry_bytes * 2);
+        assert!(disk.load("GET http://b/").is_none());
+        assert_eq!(disk.stats().evictions, 1);
+        drop(disk);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn hits_add_up_across_reads() {
+        let dir = cache_dir("hits");
+        let disk = DiskBackend::open(&dir, u64::MAX, Arc::default()).unwrap();
+        assert!(disk
+            .store(&stored("GET http://a/", b"a", i64::MAX))
+            .unwrap());
+        let now = Utc::now();
+
+        disk.get("GET http://a/").unwrap().record_hit(now);
+        disk.get("GET http://a/").unwrap().record_hit(now);
+        assert_eq!(disk.get("GET http://a/").unwrap().hits(), 2);
+
+        CacheBackend::remove(&disk, "GET http://a/");
+        assert!(disk
+            .store(&stored("GET http://a/", b"a", i64::MAX))
+            .unwrap());
+        assert_eq!(disk.get("GET http://a/").unwrap().hits(), 0);
+        drop(disk);
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,417 @@
+// imports
+use chrono::{DateTime, TimeZone, Utc};
+use http::HeaderValue;
+use std::sync::{
+    atomic::{AtomicI64, AtomicU64, Ordering},
+    Arc,
+};
+// local
+use super::{
+    cache::MapValue,
+    compression::compress_response,
+    config::{CompressionKind, StalePolicy},
+    freshness::{add_millis, add_seconds, Freshness},
+};
+use crate::http_utils::{
+    cache_control::CacheControl,
+    conditional::{has_validators, response_etag},
+    constants::CACHE_STALE_RETENTION_SEC,
+};
+
+#[derive(Debug)]
+/// A response stored in the cache, along with the metadata used for expiry, eviction and stats
+///
+/// Entries are shared (`Arc`) once inserted, so access metadata is atomic
+pub struct CachedEntry {
+    pub response: MapValue,
+    /// Freshness computed from the upstream headers when the entry was inserted
+    pub freshness: Freshness,
+    /// `etag` sent to clients: the upstream's, or generated from the body.
+    /// Kept out of `response`, a generated etag must not be sent to origin when revalidating
+    pub etag: HeaderValue,
+    /// When the entry was inserted into the cache
+    pub inserted_at: DateTime<Utc>,
+    /// Last time the entry was served from the cache, as unix timestamp in millis
+    last_access_ms: AtomicI64,
+    /// Amount of times the entry was served from the cache.
+    /// Shared with the backend when it rebuilds entries on every read, see `HitCounters`
+    hits: Arc<AtomicU64>,
+    /// Size of the headers and body, in bytes
+    pub size_bytes: usize,
+    /// Length of the body before the proxy compressed it, None if stored as received
+    pub uncompressed_body_len: Option<usize>,
+    /// When the entry stops being fresh.
+    /// Always set, even if the upstream did not send any date or expiry headers
+    pub expires_at: DateTime<Utc>,
+    /// Random offset applied to the expiry (and the stale windows after it), in millis. See `with_expiry_jitter`
+    pub expiry_jitter_ms: i64,
+    /// Upstream sent `must-revalidate`/`proxy-revalidate`: never served stale
+    pub must_revalidate: bool,
+    /// Stale entry can be served while it is revalidated in the background, until then
+    pub stale_while_revalidate_until: DateTime<Utc>,
+    /// Stale entry can be served when origin fails, until then
+    pub stale_if_error_until: DateTime<Utc>,
+    /// When the entry is removed from the cache.
+    /// Past `expires_at` if the entry can be revalidated or served stale, otherwise equal to it
+    pub retain_until: DateTime<Utc>,
+}
+
+impl CachedEntry {
+    /// Create a new entry, inserted at `now`
+    ///
+    /// `stale_policy` is the policy for the entry's route, see `CacheConfig::stale_policy`
+    pub fn new(
+        response: MapValue,
+        freshness: Freshness,
+        now: DateTime<Utc>,
+        stale_policy: &StalePolicy,
+    ) -> Self {
+        let size_bytes = response_size(&response);
+        let expires_at = freshness.expires_at();
+
+        // serving stale is not allowed when the upstream requires revalidation
+        let cache_control = CacheControl::from_headers(response.headers());
+        let must_revalidate = cache_control.must_revalidate || cache_control.proxy_revalidate;
+        let (swr_sec, sie_sec) = if must_revalidate {
+            (0, 0)
+        } else {
+            (
+                stale_policy.stale_while_revalidate(cache_control.stale_while_revalidate),
+                stale_policy.stale_if_error(cache_control.stale_if_error),
+            )
+        };
+        // saturated, the expiry can already be at `MAX_UTC` for huge lifetimes
+        let stale_while_revalidate_until = add_seconds(expires_at, swr_sec);
+        let stale_if_error_until = add_seconds(expires_at, sie_sec);
+
+        let revalidate_until = if has_validators(response.headers()) {
+            add_seconds(expires_at, CACHE_STALE_RETENTION_SEC)
+        } else {
+            expires_at
+        };
+        let retain_until = revalidate_until
+            .max(stale_while_revalidate_until)
+            .max(stale_if_error_until);
+
+        let etag = response_etag(&response);
+
+        Self {
+            response,
+            freshness,
+            etag,
+            inserted_at: now,
+            last_access_ms: AtomicI64::new(now.timestamp_millis()),
+            hits: Arc::default(),
+            size_bytes,
+            uncompressed_body_len: None,
+            expires_at,
+            expiry_jitter_ms: 0,
+            must_revalidate,
+            stale_while_revalidate_until,
+            stale_if_error_until,
+            retain_until,
+        }
+    }
+    /// Shift the entry's expiry by `jitter_ms`, so entries stored together do not all expire together
+    ///
+    /// The stale windows and retention move along with it
+    pub fn with_expiry_jitter(self, jitter_ms: i64) -> Self {
+        // saturated like the windows, see `new`
+        let jitter_ms_delta = jitter_ms.saturating_sub(self.expiry_jitter_ms);
+
+        Self {
+            expires_at: add_millis(self.expires_at, jitter_ms_delta),
+            expiry_jitter_ms: jitter_ms,
+            stale_while_revalidate_until: add_millis(
+                self.stale_while_revalidate_until,
+                jitter_ms_delta,
+            ),
+            stale_if_error_until: add_millis(self.stale_if_error_until, jitter_ms_delta),
+            retain_until: add_millis(self.retain_until, jitter_ms_delta),
+            ..self
+        }
+    }
+    /// Store the body compressed with `kind` (see `compress_response`), clients are sent it decompressed
+    /// unless they accept the encoding
+    ///
+    /// The etag stays the one of the uncompressed body
+    pub fn with_compression(self, kind: CompressionKind) -> Self {
+        match compress_response(&self.response, kind) {
+            Some(response) => Self {
+                size_bytes: response_size(&response),
+                uncompressed_body_len: Some(self.response.body().len()),
+                response,
+                ..self
+            },
+            None => self,
+        }
+    }
+    /// Count the entry's hits in `hits`, which adds up the hits of every copy of the entry
+    pub fn with_hit_counter(self, hits: Arc<AtomicU64>) -> Self {
+        Self { hits, ..self }
+    }
+    /// Carry over what a `304 Not Modified` leaves unchanged from the revalidated entry:
+    /// the length of the body before compression, and the etag generated from it if the upstream sent none
+    pub fn with_revalidated(self, stale_entry: &CachedEntry) -> Self {
+        let etag = if self.response.headers().contains_key("etag") {
+            self.etag
+        } else {
+            stale_entry.etag.clone()
+        };
+
+        Self {
+            etag,
+            uncompressed_body_len: stale_entry
+                .uncompressed_body_len
+                .or(self.uncompressed_body_len),
+            ..self
+        }
+    }
+    /// Update the access metadata when the entry is served
+    pub fn record_hit(&self, now: DateTime<Utc>) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        self.last_access_ms
+            .fetch_max(now.timestamp_millis(), Ordering::Relaxed);
+    }
+    /// Amount of times the entry was served from the cache
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+    /// Last time the entry was served from the cache
+    pub fn last_access(&self) -> DateTime<Utc> {
+        Utc.timestamp_millis_opt(self.last_access_ms.load(Ordering::Relaxed))
+            .single()
+            .unwrap_or(self.inserted_at)
+    }
+    /// Entry is past its expiry
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        now >= self.expires_at
+    }
+    /// Entry is hot and about to expire: served at least `min_hits` times,
+    /// with less than `percent` of its freshness lifetime left. Never if `percent` is 0
+    pub fn needs_refresh_ahead(&self, now: DateTime<Utc>, min_hits: u64, percent: u64) -> bool {
+        if percent == 0 || self.hits() < min_hits || self.is_expired(now) {
+            return false;
+        }
+        let remaining_ms = (self.expires_at - now).num_milliseconds();
+        let lifetime_ms = self.freshness.lifetime_sec.saturating_mul(1000);
+
+        remaining_ms.saturating_mul(100) < lifetime_ms.saturating_mul(percent as i64)
+    }
+    /// Entry can be served without contacting origin, given the client's request directives
+    ///
+    /// `no-cache`, `max-age` and `min-fresh` can require revalidating a fresh entry,
+    /// `max-stale` allows serving an expired one
+    pub fn satisfies(&self, req_cache_control: &CacheControl, now: DateTime<Utc>) -> bool {
+        if !self.meets_request_limits(req_cache_control, now) {
+            return false;
+        }
+        if !self.is_expired(now) {
+            return true;
+        }
+
+        match req_cache_control.max_stale {
+            Some(max_stale) => {
+                !self.must_revalidate && (now - self.expires_at).num_seconds() <= max_stale
+            }
+            None => false,
+        }
+    }
+    /// Entry passes the client's `no-cache`, `max-age` and `min-fresh`, expired or not
+    ///
+    /// Checked before serving stale-while-revalidate, which the client's `max-stale` does not control
+    pub fn meets_request_limits(
+        &self,
+        req_cache_control: &CacheControl,
+        now: DateTime<Utc>,
+    ) -> bool {
+        if req_cache_control.no_cache {
+            return false;
+        }
+        let age_sec = self.freshness.current_age(now);
+        if let Some(max_age) = req_cache_control.max_age {
+            // `max-age=0` always revalidates, ages are only accurate to the second
+            if max_age == 0 || age_sec > max_age {
+                return false;
+            }
+        }
+        if let Some(min_fresh) = req_cache_control.min_fresh {
+            if self.freshness.lifetime_sec - age_sec < min_fresh {
+                return false;
+            }
+        }
+
+        true
+    }
+    /// Stale entry can be served right away, and revalidated in the background
+    pub fn can_serve_stale_while_revalidate(&self, now: DateTime<Utc>) -> bool {
+        now < self.stale_while_revalidate_until
+    }
+    /// Stale entry can be served because origin failed
+    pub fn can_serve_stale_if_error(&self, now: DateTime<Utc>) -> bool {
+        now < self.stale_if_error_until
+    }
+    /// Entry is still kept in the cache, fresh or stale
+    pub fn is_retained(&self, now: DateTime<Utc>) -> bool {
+        now < self.retain_until
+    }
+}
+
+/// Size of a response's headers and body, in bytes
+pub fn response_size(res: &MapValue) -> usize {
+    let headers_size: usize = res
+        .headers()
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len())
+        .sum();
+
+    headers_size + res.body().len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cache_utils::test_utils::{entry, entry_for, response},
+        http_utils::conditional::merge_not_modified,
+    };
+
+    #[test]
+    fn windows_saturate_past_max_expiry() {
+        // received right before the end of time, fresh past it
+        let received_at = DateTime::<Utc>::MAX_UTC - chrono::Duration::seconds(10);
+        let freshness = Freshness {
+            received_at,
+            initial_age_sec: 0,
+            lifetime_sec: 60,
+            is_heuristic: false,
+        };
+        let res = response(
+            b"[]",
+            &[
+                (
+                    "cache-control",
+                    "max-age=60, stale-while-revalidate=30, stale-if-error=30",
+                ),
+                ("etag", "\"v1\""),
+            ],
+        );
+        let entry = CachedEntry::new(res, freshness, received_at, &StalePolicy::default());
+
+        assert_eq!(entry.expires_at, DateTime::<Utc>::MAX_UTC);
+        assert_eq!(entry.stale_while_revalidate_until, DateTime::<Utc>::MAX_UTC);
+        assert_eq!(entry.stale_if_error_until, DateTime::<Utc>::MAX_UTC);
+        assert_eq!(entry.retain_until, DateTime::<Utc>::MAX_UTC);
+
+        // the shift past the end of time is lost, the one back is not
+        let entry = entry.with_expiry_jitter(1500).with_expiry_jitter(-1500);
+        assert_eq!(
+            entry.expires_at,
+            DateTime::<Utc>::MAX_UTC - chrono::Duration::seconds(3)
+        );
+        assert_eq!(entry.expiry_jitter_ms, -1500);
+    }
+
+    #[test]
+    fn huge_max_age_does_not_panic() {
+        let now = Utc::now();
+        let res = response(
+            b"[]",
+            &[
+                ("cache-control", "max-age=9000000000000000"),
+                ("etag", "\"v1\""),
+            ],
+        );
+        let entry = entry_for(res, now);
+
+        assert!(!entry.is_expired(now));
+        assert!(entry.retain_until > entry.expires_at);
+    }
+
+    #[test]
+    fn revalidation_keeps_compression_metadata() {
+        let now = Utc::now();
+        let res = response(&[b'a'; 4096], &[("cache-control", "max-age=60")]);
+        let stale_entry = entry_for(res, now).with_compression(CompressionKind::Gzip);
+        assert_eq!(stale_entry.uncompressed_body_len, Some(4096));
+
+        // the stored gzip body, refreshed by a `304` without an etag
+        let mut not_modified = http::Response::new(());
+        *not_modified.status_mut() = http::StatusCode::NOT_MODIFIED;
+        not_modified
+            .headers_mut()
+            .insert("cache-control", HeaderValue::from_static("max-age=120"));
+        let refreshed_res = merge_not_modified(&stale_entry.response, &not_modified);
+        let entry = entry_for(refreshed_res, now)
+            .with_compression(CompressionKind::Gzip)
+            .with_revalidated(&stale_entry);
+
+        assert_eq!(entry.uncompressed_body_len, Some(4096));
+        assert_eq!(entry.etag, stale_entry.etag);
+        assert_eq!(entry.response.body(), stale_entry.response.body());
+    }
+
+    fn request_cache_control(value: &'static str) -> CacheControl {
+        let mut headers = http::HeaderMap::new();
+        headers.insert("cache-control", HeaderValue::from_static(value));
+        CacheControl::from_headers(&headers)
+    }
+
+    #[test]
+    fn fresh_entry_satisfies_the_client_limits() {
+        let now = Utc::now();
+        let entry = entry(now);
+        let later = now + chrono::Duration::seconds(20);
+
+        assert!(entry.satisfies(&CacheControl::default(), later));
+        assert!(!entry.satisfies(&request_cache_control("no-cache"), later));
+        assert!(!entry.satisfies(&request_cache_control("max-age=0"), now));
+        assert!(entry.satisfies(&request_cache_control("max-age=30"), later));
+        assert!(!entry.satisfies(&request_cache_control("max-age=10"), later));
+        assert!(entry.satisfies(&request_cache_control("min-fresh=30"), later));
+        assert!(!entry.satisfies(&request_cache_control("min-fresh=50"), later));
+    }
+
+    #[test]
+    fn expired_entry_satisfies_max_stale_only() {
+        let now = Utc::now();
+        let entry = entry(now);
+        let expired = now + chrono::Duration::seconds(70);
+
+        assert!(!entry.satisfies(&CacheControl::default(), expired));
+        assert!(entry.satisfies(&request_cache_control("max-stale=20"), expired));
+        assert!(!entry.satisfies(&request_cache_control("max-stale=5"), expired));
+        // any staleness without a value
+        assert!(entry.satisfies(&request_cache_control("max-stale"), expired));
+        // still within the client's limits, only not fresh
+        assert!(entry.meets_request_limits(&CacheControl::default(), expired));
+        assert!(!entry.meets_request_limits(&request_cache_control("max-age=30"), expired));
+
+        let must_revalidate = entry_for(
+            response(b"[]", &[("cache-control", "max-age=60, must-revalidate")]),
+            now,
+        );
+        assert!(!must_revalidate.satisfies(&request_cache_control("max-stale"), expired));
+    }
+
+    #[test]
+    fn refresh_ahead_needs_hits_and_little_lifetime_left() {
+        let now = Utc::now();
+        let entry = entry(now);
+        // 6s of the 60s lifetime left is exactly 10%
+        let at_boundary = now + chrono::Duration::seconds(54);
+        let past_boundary = at_boundary + chrono::Duration::milliseconds(1);
+
+        entry.record_hit(now);
+        entry.record_hit(now);
+        assert!(!entry.needs_refresh_ahead(past_boundary, 3, 10));
+        entry.record_hit(now);
+        assert!(entry.needs_refresh_ahead(past_boundary, 3, 10));
+
+        assert!(!entry.needs_refresh_ahead(at_boundary, 3, 10));
+        assert!(!entry.needs_refresh_ahead(past_boundary, 3, 0));
+        assert!(entry.needs_refresh_ahead(now + chrono::Duration::milliseconds(1), 3, 100));
+        // expired entries are revalidated instead
+        assert!(!entry.needs_refresh_ahead(now + chrono::Duration::seconds(60), 3, 100));
+    }
+}
@@ -0,0 +1,558 @@
+// imports
+use chrono::{DateTime, Utc};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::Debug,
+};
+// local
+use super::{config::EvictionKind, lru::LruOrder};
+use crate::http_utils::{
+    constants::{
+        EVICTION_SKETCH_MAX_COUNT, EVICTION_SKETCH_WIDTH, EVICTION_TINYLFU_PROTECTED_PERCENT,
+        EVICTION_TINYLFU_WINDOW_PERCENT,
+    },
+    formatting::fnv1a_hash,
+};
+
+/// Decides which entry of a cache shard is evicted when it is over its byte budget
+///
+/// Policies only track keys, the shard keeps the entries and their sizes (see `Cache`).
+pub trait EvictionPolicy: Send + Sync + Debug {
+    /// Name of the policy in logs and stats, i.e. `lru`
+    fn name(&self) -> &'static str;
+    /// A tracked key was read
+    fn on_access(&mut self, key: &str);
+    /// A key was inserted, or its entry replaced
+    fn on_insert(&mut self, key: &str);
+    /// Stop tracking a key (i.e. when its entry is removed from the cache)
+    fn on_remove(&mut self, key: &str);
+    /// Choose the key to evict and stop tracking it.
+    ///
+    /// May return the key just inserted, if the policy does not admit it
+    fn pop_victim(&mut self) -> Option<String>;
+}
+
+/// Policy for a shard, as selected in the config
+pub fn new_policy(kind: EvictionKind) -> Box<dyn EvictionPolicy> {
+    match kind {
+        EvictionKind::Lru => Box::new(LruOrder::default()),
+        EvictionKind::Lfu => Box::new(LfuOrder::default()),
+        EvictionKind::TinyLfu => Box::new(TinyLfu::default()),
+    }
+}
+
+/// Keys to evict so that `bytes_needed` are freed, chosen by `policy` after `key` was inserted into it
+///
+/// `size_of` is the size of the entry stored under a key. Nothing is removed here, so if the policy
+/// rejects `key` itself None is returned, and the keys chosen until then are tracked again
+pub fn select_victims<F>(
+    policy: &mut dyn EvictionPolicy,
+    key: &str,
+    bytes_needed: usize,
+    size_of: F,
+) -> Option<Vec<String>>
+where
+    F: Fn(&str) -> usize,
+{
+    let mut victims: Vec<String> = Vec::new();
+    let mut bytes_freed = 0;
+    while bytes_freed < bytes_needed {
+        match policy.pop_victim() {
+            Some(victim) if victim == key => {
+                for victim in &victims {
+                    policy.on_insert(victim);
+                }
+                return None;
+            }
+            Some(victim) => {
+                bytes_freed += size_of(&victim);
+                victims.push(victim);
+            }
+            None => break,
+        }
+    }
+
+    Some(victims)
+}
+
+/// Least-recently-used: evicts the key accessed the longest time ago
+impl EvictionPolicy for LruOrder {
+    fn name(&self) -> &'static str {
+        "lru"
+    }
+    fn on_access(&mut self, key: &str) {
+        self.touch(key);
+    }
+    fn on_insert(&mut self, key: &str) {
+        self.touch(key);
+    }
+    fn on_remove(&mut self, key: &str) {
+        self.remove(key);
+    }
+    fn pop_victim(&mut self) -> Option<String> {
+        self.pop_lru()
+    }
+}
+
+#[derive(Debug, Default)]
+/// Least-frequently-used: evicts the key with the fewest accesses since it was inserted,
+/// the least recently used one among equals
+pub struct LfuOrder {
+    tick: u64,
+    /// `(amount of accesses, last access tick)` by key
+    counts_by_key: HashMap<String, (u64, u64)>,
+    keys_by_count: BTreeSet<(u64, u64, String)>,
+}
+
+impl LfuOrder {
+    fn set(&mut self, key: &str, count: u64) {
+        self.tick += 1;
+        if let Some((prev_count, prev_tick)) = self
+            .counts_by_key
+            .insert(key.to_string(), (count, self.tick))
+        {
+            self.keys_by_count
+                .remove(&(prev_count, prev_tick, key.to_string()));
+        }
+        self.keys_by_count
+            .insert((count, self.tick, key.to_string()));
+    }
+}
+
+impl EvictionPolicy for LfuOrder {
+    fn name(&self) -> &'static str {
+        "lfu"
+    }
+    fn on_access(&mut self, key: &str) {
+        if let Some((count, _)) = self.counts_by_key.get(key) {
+            self.set(key, count + 1);
+        }
+    }
+    fn on_insert(&mut self, key: &str) {
+        self.set(key, 1);
+    }
+    fn on_remove(&mut self, key: &str) {
+        if let Some((count, tick)) = self.counts_by_key.remove(key) {
+            self.keys_by_count.remove(&(count, tick, key.to_string()));
+        }
+    }
+    fn pop_victim(&mut self) -> Option<String> {
+        let (_, _, key) = self.keys_by_count.pop_first()?;
+        self.counts_by_key.remove(&key);
+
+        Some(key)
+    }
+}
+
+#[derive(Debug)]
+/// Approximate access frequency of every key seen, including evicted ones (count-min sketch)
+///
+/// Counters are halved every `10 * width` increments, so old popularity fades
+pub struct FrequencySketch {
+    rows: Vec<Vec<u8>>,
+    amt_increments: usize,
+}
+
+impl Default for FrequencySketch {
+    fn default() -> Self {
+        Self {
+            rows: vec![vec![0; EVICTION_SKETCH_WIDTH]; 4],
+            amt_increments: 0,
+        }
+    }
+}
+
+impl FrequencySketch {
+    fn slots(key: &str) -> impl Iterator<Item = (usize, usize)> + '_ {
+        (0..4_u8).map(move |row| {
+            let hash = fnv1a_hash(&[&[row], key.as_bytes()]);
+            (row as usize, (hash % EVICTION_SKETCH_WIDTH as u64) as usize)
+        })
+    }
+    pub fn increment(&mut self, key: &str) {
+        for (row, col) in Self::slots(key) {
+            let counter = &mut self.rows[row][col];
+            *counter = (*counter + 1).min(EVICTION_SKETCH_MAX_COUNT);
+        }
+
+        self.amt_increments += 1;
+        if self.amt_increments >= 10 * EVICTION_SKETCH_WIDTH {
+            self.amt_increments = 0;
+            for counter in self.rows.iter_mut().flatten() {
+                *counter /= 2;
+            }
+        }
+    }
+    /// Estimated amount of accesses to a key
+    pub fn frequency(&self, key: &str) -> u8 {
+        Self::slots(key)
+            .map(|(row, col)| self.rows[row][col])
+            .min()
+            .unwrap_or(0)
+    }
+}
+
+#[derive(Debug, Default)]
+/// Window TinyLFU: new keys enter a small lru window, and only make it into the main segment
+/// if they are accessed more often than the key they would replace (by the frequency sketch).
+///
+/// The main segment is a segmented lru: keys accessed again move from probation to protected.
+/// One-off requests stay in the window, so they cannot push out frequently requested entries.
+pub struct TinyLfu {
+    sketch: FrequencySketch,
+    window: LruOrder,
+    probation: LruOrder,
+    protected: LruOrder,
+}
+
+impl TinyLfu {
+    fn len(&self) -> usize {
+        self.window.len() + self.probation.len() + self.protected.len()
+    }
+    /// Move window keys past its share into probation, they compete for admission on eviction
+    fn shrink_window(&mut self) {
+        let max_window = (self.len() * EVICTION_TINYLFU_WINDOW_PERCENT / 100).max(1);
+        while self.window.len() > max_window {
+            match self.window.pop_lru() {
+                Some(key) => self.probation.touch(&key),
+                None => break,
+            }
+        }
+    }
+    /// Demote protected keys past its share back to probation
+    fn shrink_protected(&mut self) {
+        let amt_main = self.probation.len() + self.protected.len();
+        let max_protected = amt_main * EVICTION_TINYLFU_PROTECTED_PERCENT / 100;
+        while self.protected.len() > max_protected.max(1) {
+            match self.protected.pop_lru() {
+                Some(key) => self.probation.touch(&key),
+                None => break,
+            }
+        }
+    }
+}
+
+impl EvictionPolicy for TinyLfu {
+    fn name(&self) -> &'static str {
+        "tinylfu"
+    }
+    fn on_access(&mut self, key: &str) {
+        self.sketch.increment(key);
+        if self.window.contains(key) {
+            self.window.touch(key);
+        } else if self.probation.contains(key) {
+            self.probation.remove(key);
+            self.protected.touch(key);
+            self.shrink_protected();
+        } else if self.protected.contains(key) {
+            self.protected.touch(key);
+        }
+    }
+    fn on_insert(&mut self, key: &str) {
+        self.sketch.increment(key);
+        self.on_remove(key);
+        self.window.touch(key);
+        self.shrink_window();
+    }
+    fn on_remove(&mut self, key: &str) {
+        self.window.remove(key);
+        self.probation.remove(key);
+        self.protected.remove(key);
+    }
+    /// The window's lru key (candidate) against the main segment's (victim): the least frequent one is evicted
+    fn pop_victim(&mut self) -> Option<String> {
+        let is_probation_empty = self.probation.is_empty();
+        let main = if is_probation_empty {
+            &self.protected
+        } else {
+            &self.probation
+        };
+        let (candidate, victim) = match (self.window.peek_lru(), main.peek_lru()) {
+            (Some(candidate), Some(victim)) => (candidate.clone(), victim.clone()),
+            (Some(_), None) => return self.window.pop_lru(),
+            (None, Some(_)) if is_probation_empty => return self.protected.pop_lru(),
+            (None, _) => return self.probation.pop_lru(),
+        };
+
+        if self.sketch.frequency(&candidate) <= self.sketch.frequency(&victim) {
+            return self.window.pop_lru();
+        }
+        // the candidate is admitted into probation in place of the victim
+        self.on_remove(&victim);
+        self.window.remove(&candidate);
+        self.probation.touch(&candidate);
+
+        Some(victim)
+    }
+}
+
+#[derive(Debug)]
+/// Simulation of a cache on a policy: keys and sizes only, under the same byte budget as the real cache
+struct ShadowCache {
+    policy: Box<dyn EvictionPolicy>,
+    /// `(size in bytes, retained until)` by key
+    entries: HashMap<String, (usize, DateTime<Utc>)>,
+    size_bytes: usize,
+    max_bytes: usize,
+    hits: u64,
+    lookups: u64,
+}
+
+impl ShadowCache {
+    /// Same as `Cache::insert`
+    fn insert(&mut self, key: &str, size_bytes: usize, retain_until: DateTime<Utc>) {
+        if size_bytes > self.max_bytes {
+            return;
+        }
+        let replaced_size = self
+            .entries
+            .get(key)
+            .map(|(replaced_size, _)| *replaced_size);
+        match replaced_size {
+            Some(_) => self.policy.on_access(key),
+            None => self.policy.on_insert(key),
+        }
+        let bytes_needed = (self.size_bytes - replaced_size.unwrap_or(0) + size_bytes)
+            .saturating_sub(self.max_bytes);
+        let entries = &self.entries;
+        let victims = select_victims(self.policy.as_mut(), key, bytes_needed, |victim| {
+            entries
+                .get(victim)
+                .map_or(0, |(victim_size, _)| *victim_size)
+        });
+        let Some(victims) = victims else {
+            if replaced_size.is_some() {
+                self.policy.on_insert(key);
+            }
+            return;
+        };
+        for victim in victims {
+            if let Some((victim_size, _)) = self.entries.remove(&victim) {
+                self.size_bytes -= victim_size;
+            }
+        }
+        if let Some((replaced_size, _)) = self
+            .entries
+            .insert(key.to_string(), (size_bytes, retain_until))
+        {
+            self.size_bytes -= replaced_size;
+        }
+        self.size_bytes += size_bytes;
+    }
+    fn remove(&mut self, key: &str) {
+        if let Some((size_bytes, _)) = self.entries.remove(key) {
+            self.size_bytes -= size_bytes;
+            self.policy.on_remove(key);
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Hit ratio a policy would have had on the requests seen so far
+pub struct PolicyHitRatio {
+    pub policy: &'static str,
+    pub hits: u64,
+    pub lookups: u64,
+}
+
+impl PolicyHitRatio {
+    pub fn hit_ratio(&self) -> f64 {
+        if self.lookups == 0 {
+            return 0.0;
+        }
+
+        self.hits as f64 / self.lookups as f64
+    }
+}
+
+#[derive(Debug)]
+/// Replays the cache's lookups and insertions on a shadow cache per policy, to compare their hit ratios
+///
+/// Shadow caches are not sharded and ignore `Vary`, so the ratios are only comparable with each other
+pub struct PolicyComparison {
+    shadows: Vec<ShadowCache>,
+}
+
+impl PolicyComparison {
+    /// Shadow caches for every policy, each bounded to `max_bytes`
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            shadows: [EvictionKind::Lru, EvictionKind::Lfu, EvictionKind::TinyLfu]
+                .into_iter()
+                .map(|kind| ShadowCache {
+                    policy: new_policy(kind),
+                    entries: HashMap::new(),
+                    size_bytes: 0,
+                    max_bytes,
+                    hits: 0,
+                    lookups: 0,
+                })
+                .collect(),
+        }
+    }
+    /// Replay a lookup. `found` is the size and retention of the entry the real cache had, if any:
+    /// shadow caches missing it fetch it, like the real cache would have
+    pub fn record_lookup(
+        &mut self,
+        key: &str,
+        now: DateTime<Utc>,
+        found: Option<(usize, DateTime<Utc>)>,
+    ) {
+        for shadow in &mut self.shadows {
+            shadow.lookups += 1;
+            match shadow.entries.get(key) {
+                Some((_, retain_until)) if *retain_until > now => {
+                    shadow.hits += 1;
+                    shadow.policy.on_access(key);
+                }
+                _ => match found {
+                    Some((size_bytes, retain_until)) => {
+                        shadow.insert(key, size_bytes, retain_until)
+                    }
+                    None => shadow.remove(key),
+                },
+            }
+        }
+    }
+    /// Replay an insertion of a response from origin
+    pub fn record_insert(&mut self, key: &str, size_bytes: usize, retain_until: DateTime<Utc>) {
+        for shadow in &mut self.shadows {
+            shadow.insert(key, size_bytes, retain_until);
+        }
+    }
+    /// Replay a removal on request
+    pub fn record_remove(&mut self, key: &str) {
+        for shadow in &mut self.shadows {
+            shadow.remove(key);
+        }
+    }
+    pub fn hit_ratios(&self) -> Vec<PolicyHitRatio> {
+        self.shadows
+            .iter()
+            .map(|shadow| PolicyHitRatio {
+                policy: shadow.policy.name(),
+                hits: shadow.hits,
+                lookups: shadow.lookups,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    /// Insert `key` into a cache of at most `capacity` keys, evicting with `policy`
+    fn insert(
+        policy: &mut dyn EvictionPolicy,
+        keys: &mut HashSet<String>,
+        key: &str,
+        capacity: usize,
+    ) {
+        policy.on_insert(key);
+        keys.insert(key.to_string());
+        while keys.len() > capacity {
+            let victim = policy.pop_victim().expect("keys are tracked");
+            keys.remove(&victim);
+        }
+    }
+
+    #[test]
+    fn lfu_evicts_least_frequent_then_least_recent() {
+        let mut lfu = LfuOrder::default();
+        for key in ["a", "b", "c"] {
+            lfu.on_insert(key);
+        }
+        lfu.on_access("a");
+        lfu.on_access("a");
+        lfu.on_access("c");
+        // untracked keys are ignored
+        lfu.on_access("z");
+
+        assert_eq!(lfu.pop_victim().as_deref(), Some("b"));
+        assert_eq!(lfu.pop_victim().as_deref(), Some("c"));
+        lfu.on_insert("d");
+        lfu.on_insert("e");
+        assert_eq!(lfu.pop_victim().as_deref(), Some("d"));
+        lfu.on_remove("e");
+        assert_eq!(lfu.pop_victim().as_deref(), Some("a"));
+        assert_eq!(lfu.pop_victim(), None);
+    }
+
+    #[test]
+    fn sketch_counts_are_capped_and_fade() {
+        let mut sketch = FrequencySketch::default();
+        assert_eq!(sketch.frequency("a"), 0);
+        for _ in 0..20 {
+            sketch.increment("a");
+        }
+        assert_eq!(sketch.frequency("a"), EVICTION_SKETCH_MAX_COUNT);
+
+        for i in 20..10 * EVICTION_SKETCH_WIDTH {
+            sketch.increment(&format!("other-{i}"));
+        }
+        assert_eq!(sketch.frequency("a"), EVICTION_SKETCH_MAX_COUNT / 2);
+    }
+
+    #[test]
+    fn tinylfu_admits_keys_more_frequent_than_the_victim() {
+        let mut tinylfu = TinyLfu::default();
+        let mut keys = HashSet::new();
+        insert(&mut tinylfu, &mut keys, "a", 2);
+        insert(&mut tinylfu, &mut keys, "b", 2);
+        // requested before being cached, i.e. responses too slow to be inserted yet
+        for _ in 0..5 {
+            tinylfu.on_access("c");
+        }
+        insert(&mut tinylfu, &mut keys, "c", 2);
+
+        assert!(!keys.contains("a"));
+        assert!(keys.contains("c"));
+    }
+
+    #[test]
+    fn tinylfu_keeps_frequent_keys_through_a_scan() {
+        let hot_keys: Vec<String> = (0..10).map(|i| format!("hot-{i}")).collect();
+        let scan = |policy: &mut dyn EvictionPolicy| {
+            let mut keys = HashSet::new();
+            for key in &hot_keys {
+                insert(policy, &mut keys, key, hot_keys.len());
+            }
+            for _ in 0..5 {
+                for key in &hot_keys {
+                    policy.on_access(key);
+                }
+            }
+            for i in 0..100 {
+                insert(policy, &mut keys, &format!("once-{i}"), hot_keys.len());
+            }
+
+            hot_keys.iter().filter(|key| keys.contains(*key)).count()
+        };
+
+        assert_eq!(scan(&mut TinyLfu::default()), hot_keys.len());
+        assert_eq!(scan(&mut LruOrder::default()), 0);
+    }
+
+    #[test]
+    fn comparison_replays_lookups_on_every_policy() {
+        let now = Utc::now();
+        let retain_until = now + chrono::Duration::seconds(60);
+        let mut comparison = PolicyComparison::new(100);
+        comparison.record_insert("a", 60, retain_until);
+        comparison.record_lookup("a", now, Some((60, retain_until)));
+        // does not fit along with `a`
+        comparison.record_lookup("b", now, Some((60, retain_until)));
+        comparison.record_lookup("b", now, Some((60, retain_until)));
+        comparison.record_remove("b");
+        comparison.record_lookup("b", now, None);
+
+        let hit_ratios = comparison.hit_ratios();
+        assert_eq!(hit_ratios.len(), 3);
+        for hit_ratio in hit_ratios {
+            assert_eq!(hit_ratio.lookups, 4, "{}", hit_ratio.policy);
+            assert!(hit_ratio.hits >= 1, "{}", hit_ratio.policy);
+        }
+    }
+}
@@ -0,0 +1,319 @@
+// imports
+use chrono::{DateTime, Utc};
+use http::Response;
+// local
+use super::key::vary_header_names;
+use crate::http_utils::{
+    cache_control::{parse_delta_seconds, CacheControl},
+    constants::{
+        CACHE_HEURISTIC_MAX_SEC, CACHE_HEURISTIC_PERCENT, CACHE_TTL_SEC, DELTA_SECONDS_MAX,
+    },
+    formatting::parse_http_date,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Freshness metadata for a cached response (RFC 9111 section 4.2)
+pub struct Freshness {
+    /// When the response was received from origin
+    pub received_at: DateTime<Utc>,
+    /// Age of the response at the time it was received, in seconds
+    pub initial_age_sec: i64,
+    /// How long the response stays fresh, in seconds
+    pub lifetime_sec: i64,
+    /// Lifetime was not given by the upstream, and was estimated instead
+    pub is_heuristic: bool,
+}
+
+impl Freshness {
+    /// Compute the freshness of a response received at `received_at`
+    ///
+    /// Returns None if the response must not be stored by a shared cache (`no-store`, `private`, `Vary: *`)
+    pub fn from_response<T>(res: &Response<T>, received_at: DateTime<Utc>) -> Option<Self> {
+        let header_map = res.headers();
+        let cache_control = CacheControl::from_headers(header_map);
+        if cache_control.no_store || cache_control.private {
+            return None;
+        }
+        // `Vary: *` never matches a later request
+        if vary_header_names(header_map).iter().any(|name| name == "*") {
+            return None;
+        }
+
+        let date = header_map.get("date").and_then(parse_http_date);
+        let age_sec = header_map
+            .get("age")
+            .and_then(|v| v.to_str().ok())
+            .map(|v| parse_delta_seconds(Some(v)))
+            .unwrap_or(0);
+        // apparent age corrects for upstream clocks that are behind
+        let apparent_age_sec = date
+            .map(|d| (received_at - d).num_seconds().max(0))
+            .unwrap_or(0);
+
+        let (lifetime_sec, is_heuristic) =
+            match explicit_lifetime(res, &cache_control, date.unwrap_or(received_at)) {
+                Some(lifetime) => (lifetime, false),
+                None => (heuristic_lifetime(res, date.unwrap_or(received_at)), true),
+            };
+
+        Some(Self {
+            received_at,
+            initial_age_sec: apparent_age_sec.max(age_sec),
+            lifetime_sec: lifetime_sec.clamp(0, DELTA_SECONDS_MAX),
+            is_heuristic,
+        })
+    }
+    /// Freshness of a cached error response: the upstream lifetime capped to `ttl_sec`,
+    /// or `ttl_sec` if the upstream did not give one
+    pub fn negative(self, ttl_sec: i64) -> Self {
+        let lifetime_sec = if self.is_heuristic {
+            ttl_sec
+        } else {
+            self.lifetime_sec.min(ttl_sec)
+        };
+
+        Self {
+            lifetime_sec: lifetime_sec.max(0),
+            is_heuristic: false,
+            ..self
+        }
+    }
+    /// Age of the response at `now`, in seconds
+    pub fn current_age(&self, now: DateTime<Utc>) -> i64 {
+        let resident_sec = (now - self.received_at).num_seconds().max(0);
+
+        self.initial_age_sec.saturating_add(resident_sec)
+    }
+    /// Response can still be served without revalidating
+    pub fn is_fresh(&self, now: DateTime<Utc>) -> bool {
+        self.current_age(now) < self.lifetime_sec
+    }
+    /// Amount of seconds the response has been stale for, 0 if fresh
+    pub fn staleness(&self, now: DateTime<Utc>) -> i64 {
+        (self.current_age(now) - self.lifetime_sec).max(0)
+    }
+    /// Point in time when the response stops being fresh
+    pub fn expires_at(&self) -> DateTime<Utc> {
+        let remaining_sec = self.lifetime_sec.saturating_sub(self.initial_age_sec);
+
+        add_seconds(self.received_at, remaining_sec)
+    }
+}
+
+/// `at + sec`, saturated to the range of `DateTime<Utc>` instead of panicking
+pub fn add_seconds(at: DateTime<Utc>, sec: i64) -> DateTime<Utc> {
+    add_millis(at, sec.saturating_mul(1000))
+}
+
+/// `at + ms`, saturated to the range of `DateTime<Utc>` instead of panicking
+pub fn add_millis(at: DateTime<Utc>, ms: i64) -> DateTime<Utc> {
+    // `Duration::milliseconds` panics on `i64::MIN`
+    let duration = chrono::Duration::milliseconds(ms.max(-i64::MAX));
+
+    at.checked_add_signed(duration).unwrap_or(if ms < 0 {
+        DateTime::<Utc>::MIN_UTC
+    } else {
+        DateTime::<Utc>::MAX_UTC
+    })
+}
+
+/// Lifetime set by the upstream: `s-maxage` > `max-age` > `Expires`
+///
+/// `no-cache` allows storing, but the response must be revalidated before each use
+fn explicit_lifetime<T>(
+    res: &Response<T>,
+    cache_control: &CacheControl,
+    date: DateTime<Utc>,
+) -> Option<i64> {
+    if cache_control.no_cache {
+        return Some(0);
+    }
+    if let Some(s_maxage) = cache_control.s_maxage {
+        return Some(s_maxage);
+    }
+    if let Some(max_age) = cache_control.max_age {
+        return Some(max_age);
+    }
+
+    let expires_header = res.headers().get("expires")?;
+    // an invalid `expires` value means the response is already expired
+    let expires = match parse_http_date(expires_header) {
+        Some(e) => e,
+        None => return Some(0),
+    };
+    Some((expires - date).num_seconds())
+}
+
+/// Lifetime when the upstream did not give one.
+///
+/// A fraction of the time since `Last-Modified` (capped), otherwise the default `CACHE_TTL_SEC`
+fn heuristic_lifetime<T>(res: &Response<T>, date: DateTime<Utc>) -> i64 {
+    match res.headers().get("last-modified").and_then(parse_http_date) {
+        Some(last_modified) => {
+            let modified_ago_sec = (date - last_modified).num_seconds().max(0);
+
+            (modified_ago_sec * CACHE_HEURISTIC_PERCENT / 100).min(CACHE_HEURISTIC_MAX_SEC)
+        }
+        None => CACHE_TTL_SEC,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(headers: &[(&'static str, &str)]) -> Response<Vec<u8>> {
+        let mut res = Response::new(Vec::new());
+        for (name, value) in headers {
+            res.headers_mut()
+                .insert(*name, value.parse().expect("valid header value"));
+        }
+        res
+    }
+
+    #[test]
+    fn huge_max_age_is_clamped() {
+        let now = Utc::now();
+        let res = response(&[("cache-control", "max-age=10000000000000000")]);
+        let freshness = Freshness::from_response(&res, now).expect("storable");
+
+        assert_eq!(freshness.lifetime_sec, DELTA_SECONDS_MAX);
+        assert_eq!(
+            freshness.expires_at(),
+            now + chrono::Duration::seconds(DELTA_SECONDS_MAX)
+        );
+    }
+
+    #[test]
+    fn huge_age_expires_immediately() {
+        let now = Utc::now();
+        let res = response(&[
+            ("cache-control", "max-age=60"),
+            ("age", "9999999999999999999"),
+        ]);
+        let freshness = Freshness::from_response(&res, now).expect("storable");
+
+        assert!(!freshness.is_fresh(now));
+        assert!(freshness.expires_at() <= now);
+    }
+
+    #[test]
+    fn add_seconds_saturates() {
+        let now = Utc::now();
+
+        assert_eq!(add_seconds(now, i64::MAX), DateTime::<Utc>::MAX_UTC);
+        assert_eq!(add_seconds(now, i64::MIN), DateTime::<Utc>::MIN_UTC);
+        assert_eq!(
+            add_seconds(DateTime::<Utc>::MAX_UTC, 1),
+            DateTime::<Utc>::MAX_UTC
+        );
+    }
+
+    fn http_date(at: DateTime<Utc>) -> String {
+        at.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+    }
+
+    #[test]
+    fn s_maxage_takes_precedence_over_max_age() {
+        let now = Utc::now();
+        let res = response(&[("cache-control", "max-age=60, s-maxage=120")]);
+        let freshness = Freshness::from_response(&res, now).expect("storable");
+
+        assert_eq!(freshness.lifetime_sec, 120);
+        assert!(!freshness.is_heuristic);
+    }
+
+    #[test]
+    fn expires_is_relative_to_date() {
+        // the upstream clock is an hour behind, the lifetime is not affected
+        let now = "2024-01-01T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let date = now - chrono::Duration::hours(1);
+        let res = response(&[
+            ("date", &http_date(date)),
+            ("expires", &http_date(date + chrono::Duration::seconds(300))),
+        ]);
+        let freshness = Freshness::from_response(&res, now).expect("storable");
+
+        assert_eq!(freshness.lifetime_sec, 300);
+        assert_eq!(freshness.initial_age_sec, 3600);
+        assert!(!freshness.is_fresh(now));
+
+        // invalid `expires`: already expired
+        let res = response(&[("expires", "0")]);
+        let freshness = Freshness::from_response(&res, now).expect("storable");
+        assert_eq!(freshness.lifetime_sec, 0);
+    }
+
+    #[test]
+    fn heuristic_lifetime_from_last_modified() {
+        let now = "2024-01-01T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let res = response(&[
+            ("date", &http_date(now)),
+            (
+                "last-modified",
+                &http_date(now - chrono::Duration::seconds(1000)),
+            ),
+        ]);
+        let freshness = Freshness::from_response(&res, now).expect("storable");
+        assert_eq!(freshness.lifetime_sec, 1000 * CACHE_HEURISTIC_PERCENT / 100);
+        assert!(freshness.is_heuristic);
+
+        // capped, for responses modified long ago
+        let res = response(&[(
+            "last-modified",
+            &http_date(now - chrono::Duration::days(365)),
+        )]);
+        let freshness = Freshness::from_response(&res, now).expect("storable");
+        assert_eq!(freshness.lifetime_sec, CACHE_HEURISTIC_MAX_SEC);
+
+        // no validator either: the default ttl
+        let freshness = Freshness::from_response(&response(&[]), now).expect("storable");
+        assert_eq!(freshness.lifetime_sec, CACHE_TTL_SEC);
+    }
+
+    #[test]
+    fn no_store_and_private_are_not_stored() {
+        let now = Utc::now();
+
+        for cache_control in ["no-store", "private", "max-age=60, private"] {
+            let res = response(&[("cache-control", cache_control)]);
+            assert!(
+                Freshness::from_response(&res, now).is_none(),
+                "{cache_control}"
+            );
+        }
+        let res = response(&[("cache-control", "no-cache")]);
+        let freshness = Freshness::from_response(&res, now).expect("stored, revalidated on use");
+        assert_eq!(freshness.lifetime_sec, 0);
+    }
+
+    #[test]
+    fn vary_star_is_not_stored() {
+        let res = response(&[("cache-control", "max-age=60"), ("vary", "accept, *")]);
+
+        assert!(Freshness::from_response(&res, Utc::now()).is_none());
+    }
+
+    #[test]
+    fn negative_lifetime_is_capped_to_the_ttl() {
+        let now = Utc::now();
+
+        let long_lived =
+            Freshness::from_response(&response(&[("cache-control", "max-age=600")]), now)
+                .unwrap()
+                .negative(5);
+        assert_eq!(long_lived.lifetime_sec, 5);
+        let short_lived =
+            Freshness::from_response(&response(&[("cache-control", "max-age=2")]), now)
+                .unwrap()
+                .negative(5);
+        assert_eq!(short_lived.lifetime_sec, 2);
+
+        // no lifetime from the upstream, the ttl is used instead of the heuristic
+        let heuristic = Freshness::from_response(&response(&[]), now).unwrap();
+        assert!(heuristic.is_heuristic);
+        let negative = heuristic.negative(5);
+        assert_eq!(negative.lifetime_sec, 5);
+        assert!(!negative.is_heuristic);
+    }
+}
@@ -0,0 +1,342 @@
+// imports
+use http::{HeaderMap, Method, Request};
+use std::fmt;
+use url::Url;
+// local
+use crate::http_utils::errors::{fmt_error, RequestError, Result};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+/// Key for an entry in the cache
+///
+/// Built from the request method and the canonical target url, so requests that only differ in
+/// host case, default port, query order or percent-encoding share an entry.
+/// If the stored response has a `Vary` header, the listed request headers are part of the key too.
+pub struct CacheKey {
+    pub method: Method,
+    /// Canonical target url
+    pub url: String,
+    /// `(header name, normalized value)` for each header in the response's `Vary`, sorted by name
+    pub vary: Vec<(String, String)>,
+}
+
+impl CacheKey {
+    /// Key for a request, without `Vary` headers. The target url is the request body
+    pub fn from_request(req: &Request<Vec<u8>>) -> Result<Self> {
+        let target_url = std::str::from_utf8(req.body())
+            .map_err(|_| fmt_error(RequestError::InvalidTargetUrl, "Target url is not utf-8"))?;
+
+        Ok(Self {
+            method: req.method().clone(),
+            url: canonicalize_url(target_url)?,
+            vary: Vec::new(),
+        })
+    }
+    /// Same key, varied on the request's values for `vary_names`
+    pub fn with_vary(mut self, vary_names: &[String], req_headers: &HeaderMap) -> Self {
+        self.vary = vary_names
+            .iter()
+            .map(|name| (name.clone(), normalize_header_values(req_headers, name)))
+            .collect();
+        self.vary.sort();
+
+        self
+    }
+    /// Parse a key from its string form (see `Display`)
+    pub fn parse(key: &str) -> Option<Self> {
+        let (method, rest) = key.split_once(' ')?;
+        // canonical urls never contain spaces, `Vary` headers follow the first one
+        let (url, mut rest) = rest.split_once(' ').unwrap_or((rest, ""));
+
+        let mut vary = Vec::new();
+        while !rest.is_empty() {
+            let (name, quoted_rest) = rest.split_once("=\"")?;
+            let (value, after_value) = unquote(quoted_rest)?;
+            vary.push((name.to_string(), value));
+            rest = after_value.strip_prefix(' ').unwrap_or(after_value);
+        }
+
+        Some(Self {
+            method: Method::from_bytes(method.as_bytes()).ok()?,
+            url: url.to_string(),
+            vary,
+        })
+    }
+    /// Key without the `Vary` headers, shared by every variant of a response
+    pub fn primary(&self) -> Self {
+        Self {
+            method: self.method.clone(),
+            url: self.url.clone(),
+            vary: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for CacheKey {
+    /// Canonical string form, used as the key in the cache map
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.method, self.url)?;
+        for (name, value) in &self.vary {
+            write!(f, " {name}={value:?}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Header names listed in a response's `Vary`, lowercase and sorted
+///
+/// Returns `*` as a name if the response varies on something other than headers
+pub fn vary_header_names(res_headers: &HeaderMap) -> Vec<String> {
+    let mut names: Vec<String> = res_headers
+        .get_all("vary")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+    names.sort();
+    names.dedup();
+
+    names
+}
+
+/// Canonical form of a target url
+///
+/// 1) trim surrounding whitespace, drop the fragment
+/// 1) lowercase scheme and host, remove the default port (done by `Url`)
+/// 1) normalize percent-encoding in path and query
+/// 1) sort query parameters by name, repeated parameters keep their order (`a=2&a=1` is not `a=1&a=2`)
+pub fn canonicalize_url(target_url: &str) -> Result<String> {
+    let mut url = Url::parse(target_url.trim())
+        .map_err(|e| fmt_error(RequestError::InvalidTargetUrl, &e.to_string()))?;
+    url.set_fragment(None);
+
+    let path = normalize_percent_encoding(url.path());
+    url.set_path(&path);
+
+    let query = url.query().map(|query| {
+        let mut params: Vec<String> = query
+            .split('&')
+            .filter(|param| !param.is_empty())
+            .map(normalize_percent_encoding)
+            .collect();
+        params.sort_by(|a, b| param_name(a).cmp(param_name(b)));
+        params.join("&")
+    });
+    match query {
+        Some(query) if !query.is_empty() => url.set_query(Some(&query)),
+        _ => url.set_query(None),
+    }
+
+    Ok(url.to_string())
+}
+
+/// Name of a `name=value` query parameter
+fn param_name(param: &str) -> &str {
+    param.split_once('=').map_or(param, |(name, _)| name)
+}
+
+/// Uppercase percent-encoded octets, and decode the ones that are unreserved characters (RFC 3986 section 6.2.2)
+fn normalize_percent_encoding(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut output = String::with_capacity(input.len());
+    let mut idx = 0;
+
+    while idx < bytes.len() {
+        let hex = bytes.get(idx + 1..idx + 3).and_then(|hex| {
+            std::str::from_utf8(hex)
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        });
+        match (bytes[idx], hex) {
+            (b'%', Some(octet)) => {
+                if octet.is_ascii_alphanumeric() || b"-._~".contains(&octet) {
+                    output.push(octet as char);
+                } else {
+                    output.push_str(&format!("%{octet:02X}"));
+                }
+                idx += 3;
+            }
+            (byte, _) => {
+                output.push(byte as char);
+                idx += 1;
+            }
+        }
+    }
+
+    output
+}
+
+/// Read a value written with `{:?}` up to its closing quote, returns the value and the rest of the input
+fn unquote(input: &str) -> Option<(String, &str)> {
+    let mut value = String::new();
+    let mut chars = input.char_indices();
+
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            '"' => return Some((value, &input[idx + 1..])),
+            '\\' => match chars.next()?.1 {
+                't' => value.push('\t'),
+                'n' => value.push('\n'),
+                'r' => value.push('\r'),
+                escaped => value.push(escaped),
+            },
+            _ => value.push(c),
+        }
+    }
+
+    None
+}
+
+/// Values of a request header joined and with whitespace collapsed, empty if missing
+fn normalize_header_values(req_headers: &HeaderMap, name: &str) -> String {
+    req_headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|value| value.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|value| !value.is_empty())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    fn request(target_url: &str, headers: &[(&'static str, &'static str)]) -> Request<Vec<u8>> {
+        let mut req = Request::new(target_url.as_bytes().to_vec());
+        for (name, value) in headers {
+            req.headers_mut()
+                .append(*name, HeaderValue::from_static(value));
+        }
+        req
+    }
+
+    #[test]
+    fn canonical_urls_are_shared() {
+        let canonical = canonicalize_url("http://example.com/api/blocks?b=2&a=1").unwrap();
+        assert_eq!(canonical, "http://example.com/api/blocks?a=1&b=2");
+
+        for equivalent in [
+            "  HTTP://EXAMPLE.com:80/api/blocks?b=2&a=1  ",
+            "http://example.com/api/blocks?a=1&b=2#fragment",
+            "http://example.com/api/blocks?a=1&&b=2",
+            "http://example.com/%61pi/blocks?a=1&b=2",
+        ] {
+            assert_eq!(
+                canonicalize_url(equivalent).unwrap(),
+                canonical,
+                "{equivalent}"
+            );
+        }
+        assert_ne!(
+            canonicalize_url("http://example.com/api/blocks?a=1&b=3").unwrap(),
+            canonical
+        );
+    }
+
+    #[test]
+    fn repeated_params_keep_their_order() {
+        let canonical = canonicalize_url("http://example.com/a?b=1&a=2&a=1").unwrap();
+        assert_eq!(canonical, "http://example.com/a?a=2&a=1&b=1");
+
+        assert_ne!(
+            canonicalize_url("http://example.com/a?a=1&a=2").unwrap(),
+            canonicalize_url("http://example.com/a?a=2&a=1").unwrap()
+        );
+    }
+
+    #[test]
+    fn empty_query_is_dropped() {
+        assert_eq!(
+            canonicalize_url("http://example.com/a?").unwrap(),
+            "http://example.com/a"
+        );
+        assert!(canonicalize_url("not a url").is_err());
+    }
+
+    #[test]
+    fn percent_encoding_is_normalized() {
+        // unreserved characters are decoded, others uppercased
+        assert_eq!(normalize_percent_encoding("%7euser%2fdir"), "~user%2Fdir");
+        assert_eq!(normalize_percent_encoding("a%2D%5F%2e"), "a-_.");
+        assert_eq!(normalize_percent_encoding("%20%3a"), "%20%3A");
+        // invalid or truncated escapes are kept as is
+        assert_eq!(normalize_percent_encoding("%zz%4"), "%zz%4");
+        assert_eq!(normalize_percent_encoding("100%"), "100%");
+    }
+
+    #[test]
+    fn key_round_trips() {
+        let req = request(
+            "http://example.com/api?v=1",
+            &[
+                ("accept-language", "en-US,  fr"),
+                ("x-quoted", "say \"hi\" \\ bye"),
+                ("x-trailing", "ends with backslash\\"),
+            ],
+        );
+        let vary_names = vec![
+            "x-trailing".to_string(),
+            "accept-language".to_string(),
+            "x-quoted".to_string(),
+            "x-missing".to_string(),
+        ];
+        let key = CacheKey::from_request(&req)
+            .unwrap()
+            .with_vary(&vary_names, req.headers());
+
+        assert_eq!(
+            key.vary,
+            vec![
+                ("accept-language".to_string(), "en-US,fr".to_string()),
+                ("x-missing".to_string(), String::new()),
+                ("x-quoted".to_string(), "say \"hi\" \\ bye".to_string()),
+                (
+                    "x-trailing".to_string(),
+                    "ends with backslash\\".to_string()
+                ),
+            ]
+        );
+        assert_eq!(CacheKey::parse(&key.to_string()), Some(key.clone()));
+        assert_eq!(
+            CacheKey::parse(&key.to_string())
+                .unwrap()
+                .primary()
+                .to_string(),
+            "GET http://example.com/api?v=1"
+        );
+    }
+
+    #[test]
+    fn invalid_keys_are_rejected() {
+        assert_eq!(
+            CacheKey::parse("GET http://example.com/"),
+            Some(CacheKey {
+                method: Method::GET,
+                url: "http://example.com/".to_string(),
+                vary: Vec::new(),
+            })
+        );
+        assert_eq!(CacheKey::parse("GET"), None);
+        // unterminated vary value
+        assert_eq!(CacheKey::parse("GET http://example.com/ accept=\"en"), None);
+        assert_eq!(CacheKey::parse("GET http://example.com/ accept"), None);
+    }
+
+    #[test]
+    fn vary_names_are_normalized() {
+        let mut headers = HeaderMap::new();
+        headers.append("vary", HeaderValue::from_static("Accept-Language, accept"));
+        headers.append("vary", HeaderValue::from_static("ACCEPT,"));
+
+        assert_eq!(
+            vary_header_names(&headers),
+            vec!["accept", "accept-language"]
+        );
+    }
+}
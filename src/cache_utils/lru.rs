@@ -0,0 +1,51 @@
+// imports
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Debug, Default)]
+/// Tracks the access order of the keys in the cache.
+///
+/// Every access stamps the key with a monotonically increasing tick,
+/// so the entry with the lowest tick is the least-recently-used one.
+pub struct LruOrder {
+    tick: u64,
+    ticks_by_key: HashMap<String, u64>,
+    keys_by_tick: BTreeMap<u64, String>,
+}
+
+impl LruOrder {
+    /// Mark a key as the most-recently-used entry
+    pub fn touch(&mut self, key: &str) {
+        self.tick += 1;
+        if let Some(prev_tick) = self.ticks_by_key.insert(key.to_string(), self.tick) {
+            self.keys_by_tick.remove(&prev_tick);
+        }
+        self.keys_by_tick.insert(self.tick, key.to_string());
+    }
+    /// Stop tracking a key (i.e. when its entry is removed from the cache)
+    pub fn remove(&mut self, key: &str) {
+        if let Some(prev_tick) = self.ticks_by_key.remove(key) {
+            self.keys_by_tick.remove(&prev_tick);
+        }
+    }
+    /// Least-recently-used key, still tracked
+    pub fn peek_lru(&self) -> Option<&String> {
+        self.keys_by_tick.first_key_value().map(|(_, key)| key)
+    }
+    pub fn contains(&self, key: &str) -> bool {
+        self.ticks_by_key.contains_key(key)
+    }
+    /// Remove and return the least-recently-used key
+    pub fn pop_lru(&mut self) -> Option<String> {
+        let (_, key) = self.keys_by_tick.pop_first()?;
+        self.ticks_by_key.remove(&key);
+
+        Some(key)
+    }
+    /// Amount of keys being tracked
+    pub fn len(&self) -> usize {
+        self.ticks_by_key.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.ticks_by_key.is_empty()
+    }
+}
@@ -1,2 +1,21 @@
+pub mod backend;
 pub mod cache;
+pub mod clock;
+pub mod coalesce;
+pub mod compression;
+pub mod config;
+pub mod disk;
+pub mod entry;
+pub mod eviction;
+pub mod freshness;
+pub mod key;
+pub mod lru;
+pub mod purge;
+pub mod redis;
+pub mod snapshot;
+pub mod stats;
+pub mod stored;
+#[cfg(test)]
+pub mod test_utils;
 pub mod ttl;
+pub mod warm;
@@ -0,0 +1,246 @@
+// imports
+use http::{HeaderMap, Method, Request};
+// local
+use super::{config::CacheConfig, key::canonicalize_url};
+use crate::http_utils::{
+    constants::PURGE_METHOD,
+    errors::{fmt_error, RequestError, Result},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Entries removed by a `PURGE` request, chosen by its path. The body is the target url, prefix or tag
+///
+/// 1) `/` or `/key`: every variant (`Vary`) of the target url
+/// 1) `/prefix`: every entry whose target url starts with the prefix
+/// 1) `/tag`: every entry tagged by the upstream with `surrogate-key`/`cache-tag`
+pub enum PurgeScope {
+    /// Canonical target url
+    Key(String),
+    /// Canonical url prefix
+    Prefix(String),
+    Tag(String),
+}
+
+impl PurgeScope {
+    pub fn from_request(req: &Request<Vec<u8>>) -> Result<Self> {
+        let target = std::str::from_utf8(req.body())
+            .map_err(|_| fmt_error(RequestError::InvalidTargetUrl, "Purge target is not utf-8"))?
+            .trim();
+        if target.is_empty() {
+            return Err(fmt_error(
+                RequestError::InvalidTargetUrl,
+                "Missing purge target",
+            ));
+        }
+
+        match req.uri().path() {
+            "/" | "/key" => Ok(Self::Key(canonicalize_url(target)?)),
+            "/prefix" => Ok(Self::Prefix(canonicalize_url(target)?)),
+            "/tag" => Ok(Self::Tag(target.to_string())),
+            path => Err(fmt_error(
+                path,
+                "Invalid purge path, expected /key|/prefix|/tag",
+            )),
+        }
+    }
+}
+
+/// Request uses the `PURGE` method
+pub fn is_purge_request(req: &Request<Vec<u8>>) -> bool {
+    req.method().as_str() == PURGE_METHOD
+}
+
+/// Method accepted by the proxy, see `get_parsed_request`
+pub fn is_supported_method(method: &str) -> bool {
+    method == Method::GET.as_str() || method == PURGE_METHOD
+}
+
+/// Request carries `authorization: Bearer <purge token>`.
+///
+/// Always false if no purge token is configured, purging is disabled then
+pub fn is_authorized(req: &Request<Vec<u8>>, config: &CacheConfig) -> bool {
+    let purge_token = match &config.purge_token {
+        Some(purge_token) => purge_token,
+        None => return false,
+    };
+    let bearer = req
+        .headers()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match bearer {
+        Some(bearer) => constant_time_eq(bearer.as_bytes(), purge_token.as_bytes()),
+        None => false,
+    }
+}
+
+/// Compare without returning early, so the token cannot be guessed from response times
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Surrogate tags set by the upstream: `surrogate-key` (space separated) and `cache-tag` (comma separated)
+pub fn surrogate_keys(res_headers: &HeaderMap) -> Vec<String> {
+    let space_separated = res_headers
+        .get_all("surrogate-key")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split_ascii_whitespace());
+    let comma_separated = res_headers
+        .get_all("cache-tag")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','));
+
+    space_separated
+        .chain(comma_separated)
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache_utils::{
+        cache::HTTPCache,
+        test_utils::{entry_for, response},
+    };
+    use chrono::Utc;
+
+    fn purge_request(
+        path: &str,
+        target: &str,
+        headers: &[(&'static str, &str)],
+    ) -> Request<Vec<u8>> {
+        let mut req = Request::builder().method(PURGE_METHOD).uri(path);
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.body(target.as_bytes().to_vec()).unwrap()
+    }
+
+    fn get_request(target_url: &str, headers: &[(&'static str, &str)]) -> Request<Vec<u8>> {
+        let mut req = Request::builder().uri("/");
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.body(target_url.as_bytes().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn scope_from_path() {
+        let scope = |path, target| PurgeScope::from_request(&purge_request(path, target, &[]));
+
+        assert_eq!(
+            scope("/", "http://example.com/a").unwrap(),
+            PurgeScope::Key(canonicalize_url("http://example.com/a").unwrap())
+        );
+        assert_eq!(
+            scope("/key", "http://example.com/a").unwrap(),
+            scope("/", "http://example.com/a").unwrap()
+        );
+        assert_eq!(
+            scope("/prefix", "http://example.com/api/").unwrap(),
+            PurgeScope::Prefix(canonicalize_url("http://example.com/api/").unwrap())
+        );
+        assert_eq!(
+            scope("/tag", " product-1 \n").unwrap(),
+            PurgeScope::Tag("product-1".to_string())
+        );
+        assert!(scope("/tag", "  ").is_err());
+        assert!(scope("/everything", "http://example.com/a").is_err());
+        assert!(scope("/key", "not a url").is_err());
+    }
+
+    #[test]
+    fn purge_requires_the_token() {
+        let config = CacheConfig {
+            purge_token: Some("secret".to_string()),
+            ..CacheConfig::default()
+        };
+        let authorized = |headers: &[(&'static str, &str)]| {
+            is_authorized(&purge_request("/", "http://example.com/", headers), &config)
+        };
+
+        assert!(!authorized(&[]));
+        assert!(!authorized(&[("authorization", "Bearer wrong")]));
+        assert!(!authorized(&[("authorization", "Bearer secre")]));
+        assert!(!authorized(&[("authorization", "secret")]));
+        assert!(authorized(&[("authorization", "Bearer secret")]));
+
+        // purging is disabled without a token
+        let req = purge_request("/", "http://example.com/", &[("authorization", "Bearer ")]);
+        assert!(!is_authorized(&req, &CacheConfig::default()));
+    }
+
+    #[test]
+    fn surrogate_keys_from_both_headers() {
+        let res = response(
+            b"[]",
+            &[
+                ("surrogate-key", "product-1  listing"),
+                ("cache-tag", "home, product-2,"),
+            ],
+        );
+
+        assert_eq!(
+            surrogate_keys(res.headers()),
+            ["product-1", "listing", "home", "product-2"]
+        );
+        assert!(surrogate_keys(&HeaderMap::new()).is_empty());
+    }
+
+    #[test]
+    fn purge_by_key_prefix_and_tag() {
+        let now = Utc::now();
+        let cache = HTTPCache::new();
+        let insert = |target_url: &str,
+                      req_headers: &[(&'static str, &str)],
+                      res_headers: &[(&'static str, &str)]| {
+            let mut headers = vec![("cache-control", "max-age=60")];
+            headers.extend_from_slice(res_headers);
+            let entry = entry_for(response(b"[]", &headers), now);
+            cache
+                .insert_req(&get_request(target_url, req_headers), entry)
+                .unwrap()
+                .expect("entry is stored");
+        };
+        insert(
+            "http://example.com/a",
+            &[("accept-language", "en")],
+            &[("vary", "accept-language")],
+        );
+        insert(
+            "http://example.com/a",
+            &[("accept-language", "de")],
+            &[("vary", "accept-language")],
+        );
+        insert(
+            "http://example.com/api/1",
+            &[],
+            &[("surrogate-key", "product-1")],
+        );
+        insert(
+            "http://example.com/api/2",
+            &[],
+            &[("cache-tag", "product-1,product-2")],
+        );
+        insert("http://example.com/other", &[], &[]);
+
+        // every variant of the url
+        let key_scope = PurgeScope::Key(canonicalize_url("http://example.com/a").unwrap());
+        assert_eq!(cache.purge(&key_scope), 2);
+        assert_eq!(cache.purge(&key_scope), 0);
+
+        assert_eq!(cache.purge(&PurgeScope::Tag("product-2".to_string())), 1);
+        let prefix_scope = PurgeScope::Prefix(canonicalize_url("http://example.com/api/").unwrap());
+        assert_eq!(cache.purge(&prefix_scope), 1);
+        assert_eq!(cache.purge(&PurgeScope::Tag("product-1".to_string())), 0);
+
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.stats().evictions_purge, 4);
+    }
+}
@@ -0,0 +1,408 @@
+// imports
+use chrono::{DateTime, Utc};
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::TcpStream,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+// local
+use super::{
+    backend::{BackendStats, CacheBackend, HitCounters},
+    clock::Clock,
+    config::CacheConfig,
+    entry::CachedEntry,
+    key::CacheKey,
+    stored::{read_entry, write_entry, StoredEntry},
+};
+use crate::http_utils::{
+    constants::{
+        CACHE_REDIS_KEY_PREFIX, CACHE_REDIS_MAX_DEPTH, CACHE_REDIS_TIMEOUT_MS, SIZE_MAX_BODY,
+    },
+    errors::{fmt_error, Result, StorageError},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Value in the Redis serialization protocol (RESP2)
+pub enum RespValue {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    /// None for the nil bulk string
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<RespValue>),
+}
+
+/// Write a command as an array of bulk strings
+pub fn write_command<W: Write>(writer: &mut W, args: &[&[u8]]) -> Result<()> {
+    write_value(
+        writer,
+        &RespValue::Array(
+            args.iter()
+                .map(|arg| RespValue::Bulk(Some(arg.to_vec())))
+                .collect(),
+        ),
+    )
+}
+
+/// Write a single value
+pub fn write_value<W: Write>(writer: &mut W, value: &RespValue) -> Result<()> {
+    match value {
+        RespValue::Simple(s) => write!(writer, "+{s}\r\n")?,
+        RespValue::Error(s) => write!(writer, "-{s}\r\n")?,
+        RespValue::Integer(n) => write!(writer, ":{n}\r\n")?,
+        RespValue::Bulk(None) => write!(writer, "$-1\r\n")?,
+        RespValue::Bulk(Some(bytes)) => {
+            write!(writer, "${}\r\n", bytes.len())?;
+            writer.write_all(bytes)?;
+            writer.write_all(b"\r\n")?;
+        }
+        RespValue::Array(values) => {
+            write!(writer, "*{}\r\n", values.len())?;
+            for value in values {
+                write_value(writer, value)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Read a single value, None if the connection was closed before it started
+///
+/// Arrays nested deeper than `CACHE_REDIS_MAX_DEPTH` are an error
+pub fn read_value<R: BufRead>(reader: &mut R) -> Result<Option<RespValue>> {
+    read_nested_value(reader, 0)
+}
+
+/// Read a value inside `depth` arrays
+fn read_nested_value<R: BufRead>(reader: &mut R, depth: usize) -> Result<Option<RespValue>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let line = line
+        .strip_suffix("\r\n")
+        .ok_or_else(|| fmt_error(&line, "Unterminated RESP line"))?;
+    let (kind, rest) = line.split_at(line.len().min(1));
+
+    let value = match kind {
+        "+" => RespValue::Simple(rest.to_string()),
+        "-" => RespValue::Error(rest.to_string()),
+        ":" => RespValue::Integer(parse_len(rest)?),
+        "$" => match parse_len(rest)? {
+            len if len < 0 => RespValue::Bulk(None),
+            len if len as usize > SIZE_MAX_BODY => {
+                return Err(fmt_error(len, "RESP bulk string too large"))
+            }
+            len => {
+                let mut bytes = vec![0_u8; len as usize + 2];
+                reader.read_exact(&mut bytes)?;
+                bytes.truncate(len as usize);
+                RespValue::Bulk(Some(bytes))
+            }
+        },
+        "*" if depth >= CACHE_REDIS_MAX_DEPTH => {
+            return Err(fmt_error(depth, "RESP arrays nested too deep"))
+        }
+        "*" => {
+            let len = parse_len(rest)?;
+            let mut values = Vec::new();
+            for _ in 0..len.max(0) {
+                let value = read_nested_value(reader, depth + 1)?
+                    .ok_or_else(|| fmt_error(len, "Truncated RESP array"))?;
+                values.push(value);
+            }
+            RespValue::Array(values)
+        }
+        _ => return Err(fmt_error(line, "Invalid RESP value")),
+    };
+
+    Ok(Some(value))
+}
+
+fn parse_len(value: &str) -> Result<i64> {
+    value
+        .parse::<i64>()
+        .map_err(|_| fmt_error(value, "Invalid RESP integer"))
+}
+
+#[derive(Debug)]
+/// Backend on a Redis-protocol server, so several proxies can share their cache
+///
+/// Entries are stored in the `StoredEntry` layout under `CACHE_REDIS_KEY_PREFIX` + key,
+/// with the server expiring them at the end of their retention.
+/// The server enforces its own memory budget, so evictions are not counted here.
+///
+/// A single connection is shared, and reopened on the next command after an error
+pub struct RedisBackend {
+    addr: String,
+    conn: Mutex<Option<BufReader<TcpStream>>>,
+    /// Stale policies, applied when entries are read back
+    config: Arc<CacheConfig>,
+    hits: HitCounters,
+    /// Time entries' retention is counted from, to set their expiry on the server
+    clock: Arc<dyn Clock>,
+}
+
+impl RedisBackend {
+    /// Connect to the server at `addr` (i.e. `127.0.0.1:6379`), checking it answers `PING`
+    pub fn connect(addr: &str, config: Arc<CacheConfig>, clock: Arc<dyn Clock>) -> Result<Self> {
+        let backend = Self {
+            addr: addr.to_string(),
+            conn: Mutex::new(None),
+            config,
+            hits: HitCounters::default(),
+            clock,
+        };
+        backend.command(&[b"PING"])?;
+
+        Ok(backend)
+    }
+    /// Send a command and read its reply, error replies are returned as errors
+    fn command(&self, args: &[&[u8]]) -> Result<RespValue> {
+        let mut conn = self.conn.lock().expect("Poisoned mutex: redis connection");
+        if conn.is_none() {
+            let stream = TcpStream::connect(&self.addr)?;
+            let timeout = Some(Duration::from_millis(CACHE_REDIS_TIMEOUT_MS));
+            stream.set_read_timeout(timeout)?;
+            stream.set_write_timeout(timeout)?;
+            *conn = Some(BufReader::new(stream));
+        }
+        let reader = conn.as_mut().expect("Connection opened above");
+
+        let reply = write_command(reader.get_mut(), args).and_then(|_| {
+            read_value(reader)?
+                .ok_or_else(|| fmt_error(&self.addr, "Redis server closed the connection"))
+        });
+        match reply {
+            Ok(RespValue::Error(e)) => Err(fmt_error(StorageError::BackendError(e), "Redis")),
+            Ok(reply) => Ok(reply),
+            Err(e) => {
+                // the stream may be mid-reply, start over on a new one
+                *conn = None;
+                Err(e)
+            }
+        }
+    }
+    fn server_key(key: &str) -> Vec<u8> {
+        format!("{CACHE_REDIS_KEY_PREFIX}{key}").into_bytes()
+    }
+}
+
+impl CacheBackend for RedisBackend {
+    fn name(&self) -> &'static str {
+        "redis"
+    }
+    fn get(&self, key: &str) -> Option<Arc<CachedEntry>> {
+        let bytes = match self.command(&[b"GET", &Self::server_key(key)]) {
+            Ok(RespValue::Bulk(Some(bytes))) => bytes,
+            Ok(_) => return None,
+            Err(e) => {
+                eprintln!("error reading entry from redis cache: {e}");
+                return None;
+            }
+        };
+        let target_url = CacheKey::parse(key)?.url;
+
+        let entry = read_entry(&mut bytes.as_slice()).and_then(|stored| {
+            stored
+                .ok_or_else(|| fmt_error(StorageError::InvalidEntry, "Empty value"))?
+                .into_entry(self.config.stale_policy(&target_url))
+        });
+        match entry {
+            Ok(entry) => Some(Arc::new(self.hits.attach(key, entry))),
+            Err(e) => {
+                eprintln!("invalid redis cache entry for {key} ({e})... skipping");
+                self.remove(key);
+                None
+            }
+        }
+    }
+    fn insert(&self, key: String, entry: Arc<CachedEntry>) -> bool {
+        let ttl_ms = entry
+            .retain_until
+            .signed_duration_since(self.clock.now())
+            .num_milliseconds();
+        if ttl_ms <= 0 {
+            return false;
+        }
+        let mut bytes = Vec::new();
+        if let Err(e) = write_entry(&mut bytes, &StoredEntry::from_entry(&key, &entry)) {
+            eprintln!("error encoding entry for redis cache: {e}");
+            return false;
+        }
+
+        let ttl_ms = ttl_ms.to_string();
+        let reply = self.command(&[
+            b"SET",
+            &Self::server_key(&key),
+            &bytes,
+            b"PX",
+            ttl_ms.as_bytes(),
+        ]);
+        match reply {
+            Ok(_) => true,
+            Err(e) => {
+                eprintln!("error writing entry to redis cache: {e}");
+                false
+            }
+        }
+    }
+    fn remove(&self, key: &str) -> bool {
+        self.hits.remove(key);
+        matches!(
+            self.command(&[b"DEL", &Self::server_key(key)]),
+            Ok(RespValue::Integer(n)) if n > 0
+        )
+    }
+    /// Walks the keyspace with `SCAN`, only keys under `CACHE_REDIS_KEY_PREFIX`
+    fn keys(&self) -> Vec<String> {
+        let pattern = format!("{CACHE_REDIS_KEY_PREFIX}*");
+        let mut keys = Vec::new();
+        let mut cursor = String::from("0");
+
+        loop {
+            let reply = self.command(&[
+                b"SCAN",
+                cursor.as_bytes(),
+                b"MATCH",
+                pattern.as_bytes(),
+                b"COUNT",
+                b"100",
+            ]);
+            let (next_cursor, batch) = match reply {
+                Ok(RespValue::Array(mut reply)) if reply.len() == 2 => {
+                    match (reply.remove(0), reply.remove(0)) {
+                        (RespValue::Bulk(Some(next_cursor)), RespValue::Array(batch)) => {
+                            (next_cursor, batch)
+                        }
+                        _ => break,
+                    }
+                }
+                Ok(_) => break,
+                Err(e) => {
+                    eprintln!("error listing redis cache keys: {e}");
+                    break;
+                }
+            };
+
+            keys.extend(batch.into_iter().filter_map(|key| {
+                match key {
+                    RespValue::Bulk(Some(key)) => String::from_utf8(key)
+                        .ok()?
+                        .strip_prefix(CACHE_REDIS_KEY_PREFIX)
+                        .map(String::from),
+                    _ => None,
+                }
+            }));
+            cursor = String::from_utf8_lossy(&next_cursor).to_string();
+            if cursor == "0" {
+                break;
+            }
+        }
+
+        keys
+    }
+    /// Entries are expired by the server, only their hit counters are dropped
+    fn purge_expired(&self, now: DateTime<Utc>) -> usize {
+        self.hits.purge(now);
+        0
+    }
+    /// Server-wide `DBSIZE` and `used_memory`, two commands instead of walking the keyspace.
+    /// Keys and memory of anything else stored on the server are included
+    fn stats(&self) -> BackendStats {
+        let entries = match self.command(&[b"DBSIZE"]) {
+            Ok(RespValue::Integer(amt_keys)) => amt_keys.max(0) as usize,
+            _ => 0,
+        };
+        let size_bytes = match self.command(&[b"INFO", b"memory"]) {
+            Ok(RespValue::Bulk(Some(info))) => parse_used_memory(&String::from_utf8_lossy(&info)),
+            _ => 0,
+        };
+
+        BackendStats {
+            backend: self.name(),
+            entries,
+            size_bytes,
+            evictions: 0,
+            lower_tier: None,
+        }
+    }
+}
+
+/// `used_memory` in the reply to `INFO memory`, 0 if missing
+fn parse_used_memory(info: &str) -> u64 {
+    info.lines()
+        .find_map(|line| line.strip_prefix("used_memory:"))
+        .and_then(|used_memory| used_memory.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(value: &RespValue) -> RespValue {
+        let mut bytes = Vec::new();
+        write_value(&mut bytes, value).unwrap();
+
+        read_value(&mut bytes.as_slice()).unwrap().unwrap()
+    }
+
+    #[test]
+    fn values_round_trip() {
+        for value in [
+            RespValue::Simple("OK".to_string()),
+            RespValue::Error("ERR unknown command".to_string()),
+            RespValue::Integer(-42),
+            RespValue::Bulk(None),
+            RespValue::Bulk(Some(b"binary\r\nbody".to_vec())),
+            RespValue::Bulk(Some(Vec::new())),
+            RespValue::Array(Vec::new()),
+            RespValue::Array(vec![
+                RespValue::Bulk(Some(b"0".to_vec())),
+                RespValue::Array(vec![RespValue::Bulk(None), RespValue::Integer(1)]),
+            ]),
+        ] {
+            assert_eq!(round_trip(&value), value);
+        }
+    }
+
+    #[test]
+    fn invalid_values_are_errors() {
+        // closed before a value started
+        assert!(read_value(&mut b"".as_slice()).unwrap().is_none());
+
+        assert!(read_value(&mut b"+OK".as_slice()).is_err());
+        assert!(read_value(&mut b"+OK\n".as_slice()).is_err());
+        assert!(read_value(&mut b"?what\r\n".as_slice()).is_err());
+        assert!(read_value(&mut b":abc\r\n".as_slice()).is_err());
+        // bulk string shorter than announced, or bigger than any body
+        assert!(read_value(&mut b"$10\r\nshort\r\n".as_slice()).is_err());
+        let oversized = format!("${}\r\n", SIZE_MAX_BODY + 1);
+        assert!(read_value(&mut oversized.as_bytes()).is_err());
+        // array with fewer elements than announced
+        assert!(read_value(&mut b"*2\r\n:1\r\n".as_slice()).is_err());
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let mut value = RespValue::Integer(1);
+        for _ in 0..CACHE_REDIS_MAX_DEPTH {
+            value = RespValue::Array(vec![value]);
+        }
+        assert_eq!(round_trip(&value), value);
+
+        let mut bytes = Vec::new();
+        write_value(&mut bytes, &RespValue::Array(vec![value])).unwrap();
+        assert!(read_value(&mut bytes.as_slice()).is_err());
+    }
+
+    #[test]
+    fn used_memory_from_info() {
+        let info = "# Memory\r\nused_memory:1024\r\nused_memory_human:1.00K\r\n";
+        assert_eq!(parse_used_memory(info), 1024);
+        assert_eq!(parse_used_memory("# Memory\r\n"), 0);
+    }
+}
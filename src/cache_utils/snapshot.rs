@@ -0,0 +1,191 @@
+// imports
+use signal_hook::{
+    consts::{SIGINT, SIGTERM, SIGUSR1},
+    iterator::Signals,
+};
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    process::exit,
+    sync::Arc,
+    thread::{self, JoinHandle},
+};
+// local
+use super::{
+    cache::HTTPCache,
+    stored::{read_entry, write_entry, StoredEntry},
+};
+use crate::http_utils::errors::{fmt_error, Result, StorageError};
+
+/// Magic bytes at the start of a snapshot file
+const SNAPSHOT_MAGIC: &[u8; 4] = b"RPXS";
+/// Bumped whenever the snapshot layout changes, independently of `STORED_ENTRY_VERSION`
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+/// Write every entry in the cache to a snapshot file
+///
+/// Layout: magic, version, amount of entries, then each entry (see `write_entry`).
+/// Written to a temporary file first, so an interrupted export never replaces a good snapshot.
+///
+/// Returns the amount of entries written
+pub fn export_snapshot(cache: &HTTPCache, path: &Path) -> Result<usize> {
+    let entries = cache.entries();
+    let tmp_path = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+
+    writer.write_all(SNAPSHOT_MAGIC)?;
+    writer.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+    writer.write_all(&(entries.len() as u64).to_le_bytes())?;
+    for (key, entry) in &entries {
+        write_entry(&mut writer, &StoredEntry::from_entry(key, entry))?;
+    }
+    writer.flush()?;
+    drop(writer);
+    fs::rename(tmp_path, path)?;
+
+    Ok(entries.len())
+}
+
+/// Load the entries of a snapshot file into the cache
+///
+/// Entries no longer retained, or not fitting in the cache's byte budget, are skipped.
+/// Loading stops at the first corrupt entry, entries before it are kept.
+///
+/// Returns the amount of entries loaded
+pub fn import_snapshot(cache: &HTTPCache, path: &Path) -> Result<usize> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    let mut header = [0_u8; 4 + 2 + 8];
+    reader.read_exact(&mut header)?;
+    if &header[0..4] != SNAPSHOT_MAGIC {
+        return Err(fmt_error(StorageError::InvalidMagic, "Reading snapshot"));
+    }
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    if version != SNAPSHOT_VERSION {
+        return Err(fmt_error(
+            StorageError::UnsupportedVersion(version),
+            "Reading snapshot",
+        ));
+    }
+    let amt_entries = u64::from_le_bytes(header[6..14].try_into()?);
+
+    let dt_now = cache.now();
+    let mut amt_loaded = 0;
+    for _ in 0..amt_entries {
+        let stored = match read_entry(&mut reader) {
+            Ok(Some(stored)) => stored,
+            Ok(None) => {
+                eprintln!("snapshot is truncated... stopping after {amt_loaded} entries");
+                break;
+            }
+            Err(e) => {
+                eprintln!("corrupt snapshot entry ({e})... stopping after {amt_loaded} entries");
+                break;
+            }
+        };
+        if !stored.is_retained(dt_now) {
+            continue;
+        }
+        match cache.restore(stored, false) {
+            Ok(Some(_)) => amt_loaded += 1,
+            Ok(None) => {}
+            Err(e) => eprintln!("invalid snapshot entry ({e})... skipping"),
+        }
+    }
+
+    Ok(amt_loaded)
+}
+
+/// Spawn a thread exporting a snapshot on `SIGUSR1`, and on `SIGINT`/`SIGTERM` before exiting
+pub fn spawn_snapshot_signal_handler(
+    cache: Arc<HTTPCache>,
+    path: PathBuf,
+) -> Result<JoinHandle<()>> {
+    let mut signals = Signals::new([SIGUSR1, SIGINT, SIGTERM])?;
+
+    Ok(thread::spawn(move || {
+        for signal in signals.forever() {
+            match export_snapshot(&cache, &path) {
+                Ok(amt_entries) => println!("Saved {amt_entries} entries to snapshot {path:?}"),
+                Err(e) => eprintln!("error saving snapshot {path:?}: {e}"),
+            }
+            if signal != SIGUSR1 {
+                exit(0);
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache_utils::test_utils::{entry_for, response};
+    use chrono::{TimeZone, Utc};
+
+    fn request(
+        target_url: &str,
+        headers: &[(&'static str, &'static str)],
+    ) -> http::Request<Vec<u8>> {
+        let mut req = http::Request::new(target_url.as_bytes().to_vec());
+        for (name, value) in headers {
+            req.headers_mut()
+                .insert(*name, http::HeaderValue::from_static(value));
+        }
+        req
+    }
+
+    #[test]
+    fn export_then_import_restores_entries_and_vary() {
+        // stored timestamps are in millis
+        let now = Utc
+            .timestamp_millis_opt(Utc::now().timestamp_millis())
+            .unwrap();
+        let cache = HTTPCache::new();
+        let plain_req = request("http://example.com/a", &[]);
+        let varied_req = request("http://example.com/b", &[("accept-language", "en")]);
+        let plain = entry_for(
+            response(b"a", &[("cache-control", "max-age=60"), ("etag", "\"a\"")]),
+            now,
+        );
+        let varied = entry_for(
+            response(
+                b"b",
+                &[("cache-control", "max-age=60"), ("vary", "accept-language")],
+            ),
+            now,
+        );
+        cache.insert_req(&plain_req, plain).unwrap();
+        cache.insert_req(&varied_req, varied).unwrap();
+
+        let path = std::env::temp_dir().join(format!("snapshot-{}.bin", std::process::id()));
+        assert_eq!(export_snapshot(&cache, &path).unwrap(), 2);
+        let restored = HTTPCache::new();
+        assert_eq!(import_snapshot(&restored, &path).unwrap(), 2);
+        fs::remove_file(&path).ok();
+
+        for req in [&plain_req, &varied_req] {
+            let key = restored.key_for_request(req).unwrap();
+            assert_eq!(key, cache.key_for_request(req).unwrap());
+            let original = cache.peek(&key).unwrap();
+            let entry = restored.peek(&key).expect("entry is restored");
+            assert_eq!(entry.response.body(), original.response.body());
+            assert_eq!(entry.response.headers(), original.response.headers());
+            assert_eq!(entry.etag, original.etag);
+            assert_eq!(entry.expires_at, original.expires_at);
+        }
+        // the vary index came along, other languages still miss
+        let other_language = request("http://example.com/b", &[("accept-language", "de")]);
+        let key = restored.key_for_request(&other_language).unwrap();
+        assert!(restored.peek(&key).is_none());
+    }
+
+    #[test]
+    fn import_rejects_other_files() {
+        let path = std::env::temp_dir().join(format!("snapshot-bad-{}.bin", std::process::id()));
+        fs::write(&path, b"not a snapshot file").unwrap();
+
+        assert!(import_snapshot(&HTTPCache::new(), &path).is_err());
+        fs::remove_file(&path).ok();
+    }
+}
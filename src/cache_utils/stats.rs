@@ -0,0 +1,217 @@
+// imports
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+// local
+use super::{backend::BackendStats, eviction::PolicyHitRatio};
+
+#[derive(Debug, Default)]
+/// Counters updated by `HTTPCache`, shared by every thread handling a request
+pub struct CacheCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    stale_hits: AtomicU64,
+    insertions: AtomicU64,
+    evictions_ttl: AtomicU64,
+    evictions_purge: AtomicU64,
+    uncompressed_body_bytes: AtomicU64,
+    compressed_body_bytes: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Kind of event counted in `CacheCounters`
+pub enum CacheEvent {
+    /// Lookup found a fresh entry
+    Hit,
+    /// Lookup found nothing usable
+    Miss,
+    /// Lookup found an expired entry still retained, served stale or revalidated
+    StaleHit,
+    /// Response from origin stored in the backend
+    Insertion,
+    /// Entry removed past its expiry and retention
+    EvictionTtl,
+    /// Entry removed on request (i.e. `PURGE`)
+    EvictionPurge,
+}
+
+impl CacheCounters {
+    pub fn record(&self, event: CacheEvent) {
+        self.record_n(event, 1);
+    }
+    pub fn record_n(&self, event: CacheEvent, amt: u64) {
+        let counter = match event {
+            CacheEvent::Hit => &self.hits,
+            CacheEvent::Miss => &self.misses,
+            CacheEvent::StaleHit => &self.stale_hits,
+            CacheEvent::Insertion => &self.insertions,
+            CacheEvent::EvictionTtl => &self.evictions_ttl,
+            CacheEvent::EvictionPurge => &self.evictions_purge,
+        };
+        counter.fetch_add(amt, Ordering::Relaxed);
+    }
+    /// Body of an inserted entry compressed from `uncompressed_len` to `compressed_len` bytes
+    pub fn record_compression(&self, uncompressed_len: usize, compressed_len: usize) {
+        self.uncompressed_body_bytes
+            .fetch_add(uncompressed_len as u64, Ordering::Relaxed);
+        self.compressed_body_bytes
+            .fetch_add(compressed_len as u64, Ordering::Relaxed);
+    }
+    /// Current values, along with the backend's size and capacity evictions
+    ///
+    /// Counters are read one at a time, so the snapshot may be off by in-progress requests
+    pub fn snapshot(&self, backend: BackendStats) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            stale_hits: self.stale_hits.load(Ordering::Relaxed),
+            insertions: self.insertions.load(Ordering::Relaxed),
+            evictions_ttl: self.evictions_ttl.load(Ordering::Relaxed),
+            evictions_capacity: backend.evictions,
+            evictions_purge: self.evictions_purge.load(Ordering::Relaxed),
+            entries: backend.entries,
+            size_bytes: backend.size_bytes,
+            uncompressed_body_bytes: self.uncompressed_body_bytes.load(Ordering::Relaxed),
+            compressed_body_bytes: self.compressed_body_bytes.load(Ordering::Relaxed),
+            backend,
+            policy_hit_ratios: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+/// Snapshot of the cache's counters, see `HTTPCache::stats`
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub stale_hits: u64,
+    pub insertions: u64,
+    /// Entries removed past their expiry and retention
+    pub evictions_ttl: u64,
+    /// Entries removed to make room for new ones, counted by the backend
+    pub evictions_capacity: u64,
+    /// Entries removed on request
+    pub evictions_purge: u64,
+    /// Current amount of entries
+    pub entries: usize,
+    /// Current size of the entries, in bytes
+    pub size_bytes: u64,
+    /// Bodies compressed on insert, before compression
+    pub uncompressed_body_bytes: u64,
+    /// Bodies compressed on insert, after compression
+    pub compressed_body_bytes: u64,
+    /// Size of each tier, for tiered backends
+    pub backend: BackendStats,
+    /// Hit ratio each eviction policy would have had, see `PolicyComparison`
+    pub policy_hit_ratios: Vec<PolicyHitRatio>,
+}
+
+impl CacheStats {
+    /// Lookups answered from the cache (fresh or stale), over all lookups
+    pub fn hit_ratio(&self) -> f64 {
+        let amt_lookups = self.hits + self.stale_hits + self.misses;
+        if amt_lookups == 0 {
+            return 0.0;
+        }
+
+        (self.hits + self.stale_hits) as f64 / amt_lookups as f64
+    }
+    /// Size of the bodies compressed on insert over their compressed size, 0 if none were
+    pub fn compression_ratio(&self) -> f64 {
+        if self.compressed_body_bytes == 0 {
+            return 0.0;
+        }
+
+        self.uncompressed_body_bytes as f64 / self.compressed_body_bytes as f64
+    }
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} hits, {} stale hits, {} misses ({:.1}% hit ratio), {} insertions, \
+             evictions: {} ttl / {} capacity / {} purge, {} entries ({} bytes) in {}",
+            self.hits,
+            self.stale_hits,
+            self.misses,
+            self.hit_ratio() * 100.0,
+            self.insertions,
+            self.evictions_ttl,
+            self.evictions_capacity,
+            self.evictions_purge,
+            self.entries,
+            self.size_bytes,
+            self.backend.backend
+        )?;
+        if self.compressed_body_bytes > 0 {
+            write!(
+                f,
+                ", compression {:.1}x ({} -> {} bytes)",
+                self.compression_ratio(),
+                self.uncompressed_body_bytes,
+                self.compressed_body_bytes
+            )?;
+        }
+        for (idx, policy) in self.policy_hit_ratios.iter().enumerate() {
+            let separator = if idx == 0 { ", policies:" } else { " /" };
+            write!(
+                f,
+                "{separator} {} {:.1}%",
+                policy.policy,
+                policy.hit_ratio() * 100.0
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_are_snapshotted_with_the_backend_stats() {
+        let counters = CacheCounters::default();
+        counters.record(CacheEvent::Hit);
+        counters.record_n(CacheEvent::Hit, 2);
+        counters.record(CacheEvent::Miss);
+        counters.record(CacheEvent::StaleHit);
+        counters.record(CacheEvent::Insertion);
+        counters.record_n(CacheEvent::EvictionPurge, 3);
+
+        let backend = BackendStats {
+            backend: "memory",
+            entries: 4,
+            size_bytes: 2048,
+            evictions: 5,
+            lower_tier: None,
+        };
+        let stats = counters.snapshot(backend.clone());
+        assert_eq!(stats.hits, 3);
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.stale_hits, 1);
+        assert_eq!(stats.insertions, 1);
+        assert_eq!(stats.evictions_ttl, 0);
+        assert_eq!(stats.evictions_purge, 3);
+        assert_eq!(stats.evictions_capacity, 5);
+        assert_eq!(stats.entries, 4);
+        assert_eq!(stats.size_bytes, 2048);
+        assert_eq!(stats.backend, backend);
+
+        // stale hits count as answered from the cache
+        assert_eq!(stats.hit_ratio(), 0.8);
+    }
+
+    #[test]
+    fn hit_ratio_is_zero_without_requests() {
+        let stats = CacheCounters::default().snapshot(BackendStats::default());
+
+        assert_eq!(stats.hit_ratio(), 0.0);
+        assert!(stats
+            .to_string()
+            .starts_with("0 hits, 0 stale hits, 0 misses (0.0% hit ratio)"));
+    }
+}
@@ -0,0 +1,252 @@
+// imports
+use bytes::Bytes;
+use chrono::{DateTime, TimeZone, Utc};
+use http::{header::HeaderName, HeaderValue, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::io::{ErrorKind, Read, Write};
+// local
+use super::{cache::MapValue, config::StalePolicy, entry::CachedEntry, freshness::Freshness};
+use crate::http_utils::{
+    constants::SIZE_MAX_BODY,
+    errors::{fmt_error, Result, StorageError},
+    formatting::fnv1a_hash,
+};
+
+/// Magic bytes at the start of every stored entry
+const STORED_ENTRY_MAGIC: &[u8; 4] = b"RPXE";
+/// Bumped whenever the layout of `StoredEntry` changes
+pub const STORED_ENTRY_VERSION: u16 = 1;
+/// magic + version + metadata length + body length + checksum
+const STORED_ENTRY_HEADER_LEN: usize = 4 + 2 + 4 + 8 + 8;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Serializable form of a cached entry: its key, the response and the metadata its expiry is derived from
+///
+/// Hits and last access are not kept, the rest of the entry's metadata is recomputed when restored
+pub struct StoredEntry {
+    pub key: String,
+    pub status: u16,
+    /// Header values are kept as bytes, they are not guaranteed to be utf-8
+    pub headers: Vec<(String, Vec<u8>)>,
+    pub received_at_ms: i64,
+    pub initial_age_sec: i64,
+    pub lifetime_sec: i64,
+    pub is_heuristic: bool,
+    pub inserted_at_ms: i64,
+    /// Missing in entries stored before expiry jitter existed
+    #[serde(default)]
+    pub expiry_jitter_ms: i64,
+    /// Body was compressed by the proxy, from this length
+    #[serde(default)]
+    pub uncompressed_body_len: Option<usize>,
+    /// Kept for compressed bodies, their etag cannot be generated from the stored body
+    #[serde(default)]
+    pub etag: Option<Vec<u8>>,
+    /// When the entry stops being retained, used to skip expired entries without decoding them
+    pub retain_until_ms: i64,
+    /// Written after the metadata, as raw bytes
+    #[serde(skip)]
+    pub body: Bytes,
+}
+
+impl StoredEntry {
+    /// Serializable copy of a cached entry, the body is shared
+    pub fn from_entry(key: &str, entry: &CachedEntry) -> Self {
+        let res = &entry.response;
+
+        Self {
+            key: key.to_string(),
+            status: res.status().as_u16(),
+            headers: res
+                .headers()
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.as_bytes().to_vec()))
+                .collect(),
+            received_at_ms: entry.freshness.received_at.timestamp_millis(),
+            initial_age_sec: entry.freshness.initial_age_sec,
+            lifetime_sec: entry.freshness.lifetime_sec,
+            is_heuristic: entry.freshness.is_heuristic,
+            inserted_at_ms: entry.inserted_at.timestamp_millis(),
+            expiry_jitter_ms: entry.expiry_jitter_ms,
+            uncompressed_body_len: entry.uncompressed_body_len,
+            etag: entry
+                .uncompressed_body_len
+                .map(|_| entry.etag.as_bytes().to_vec()),
+            retain_until_ms: entry.retain_until.timestamp_millis(),
+            body: res.body().clone(),
+        }
+    }
+    /// Entry is still retained at `now`
+    pub fn is_retained(&self, now: DateTime<Utc>) -> bool {
+        now.timestamp_millis() < self.retain_until_ms
+    }
+    /// Rebuild the cached entry, with the stale policy of its route
+    ///
+    /// Expiry is derived from the stored freshness and jitter, so the entry keeps its original ttl
+    pub fn into_entry(self, stale_policy: &StalePolicy) -> Result<CachedEntry> {
+        let mut response = MapValue::new(self.body);
+        *response.status_mut() = StatusCode::from_u16(self.status)
+            .map_err(|_| fmt_error(StorageError::InvalidEntry, "Invalid status"))?;
+        for (name, value) in &self.headers {
+            let header_name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| fmt_error(StorageError::InvalidEntry, "Invalid header name"))?;
+            let header_value = HeaderValue::from_bytes(value)
+                .map_err(|_| fmt_error(StorageError::InvalidEntry, "Invalid header value"))?;
+            response.headers_mut().append(header_name, header_value);
+        }
+
+        let freshness = Freshness {
+            received_at: timestamp_from_millis(self.received_at_ms)?,
+            initial_age_sec: self.initial_age_sec,
+            lifetime_sec: self.lifetime_sec,
+            is_heuristic: self.is_heuristic,
+        };
+
+        let mut entry = CachedEntry::new(
+            response,
+            freshness,
+            timestamp_from_millis(self.inserted_at_ms)?,
+            stale_policy,
+        )
+        .with_expiry_jitter(self.expiry_jitter_ms);
+        entry.uncompressed_body_len = self.uncompressed_body_len;
+        if let Some(etag) = &self.etag {
+            entry.etag = HeaderValue::from_bytes(etag)
+                .map_err(|_| fmt_error(StorageError::InvalidEntry, "Invalid etag"))?;
+        }
+
+        Ok(entry)
+    }
+}
+
+/// Timestamp stored as unix millis, an error if out of range
+fn timestamp_from_millis(timestamp_ms: i64) -> Result<DateTime<Utc>> {
+    Utc.timestamp_millis_opt(timestamp_ms)
+        .single()
+        .ok_or_else(|| fmt_error(StorageError::InvalidEntry, "Invalid timestamp"))
+}
+
+/// Write a stored entry
+///
+/// Layout: magic, version, metadata length, body length, checksum (FNV-1a of metadata and body),
+/// metadata (json), body
+pub fn write_entry<W: Write>(writer: &mut W, stored: &StoredEntry) -> Result<()> {
+    let meta = serde_json::to_vec(stored)?;
+    let checksum = fnv1a_hash(&[&meta, &stored.body]);
+
+    writer.write_all(STORED_ENTRY_MAGIC)?;
+    writer.write_all(&STORED_ENTRY_VERSION.to_le_bytes())?;
+    writer.write_all(&(meta.len() as u32).to_le_bytes())?;
+    writer.write_all(&(stored.body.len() as u64).to_le_bytes())?;
+    writer.write_all(&checksum.to_le_bytes())?;
+    writer.write_all(&meta)?;
+    writer.write_all(&stored.body)?;
+
+    Ok(())
+}
+
+/// Read a stored entry, verifying its checksum
+///
+/// Returns None if the reader is at its end
+pub fn read_entry<R: Read>(reader: &mut R) -> Result<Option<StoredEntry>> {
+    let mut header = [0_u8; STORED_ENTRY_HEADER_LEN];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    if &header[0..4] != STORED_ENTRY_MAGIC {
+        return Err(fmt_error(
+            StorageError::InvalidMagic,
+            "Reading stored entry",
+        ));
+    }
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    if version != STORED_ENTRY_VERSION {
+        return Err(fmt_error(
+            StorageError::UnsupportedVersion(version),
+            "Reading stored entry",
+        ));
+    }
+    let meta_len = u32::from_le_bytes(header[6..10].try_into()?) as usize;
+    let body_len = u64::from_le_bytes(header[10..18].try_into()?) as usize;
+    let checksum = u64::from_le_bytes(header[18..26].try_into()?);
+    // a corrupt header must not make us allocate an arbitrary amount
+    if meta_len > SIZE_MAX_BODY || body_len > SIZE_MAX_BODY {
+        return Err(fmt_error(
+            StorageError::InvalidEntry,
+            "Stored entry too large",
+        ));
+    }
+
+    let mut meta = vec![0_u8; meta_len];
+    let mut body = vec![0_u8; body_len];
+    reader
+        .read_exact(&mut meta)
+        .and_then(|_| reader.read_exact(&mut body))
+        .map_err(|e| fmt_error(StorageError::InvalidEntry, &e.to_string()))?;
+    if fnv1a_hash(&[&meta, &body]) != checksum {
+        return Err(fmt_error(
+            StorageError::ChecksumMismatch,
+            "Reading stored entry",
+        ));
+    }
+
+    let mut stored: StoredEntry = serde_json::from_slice(&meta)
+        .map_err(|e| fmt_error(StorageError::InvalidEntry, &e.to_string()))?;
+    stored.body = Bytes::from(body);
+
+    Ok(Some(stored))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache_utils::test_utils::entry;
+
+    #[test]
+    fn entry_round_trips() {
+        let now = Utc.timestamp_millis_opt(1_700_000_000_123).unwrap();
+        let entry = entry(now).with_expiry_jitter(-1500);
+        let stored = StoredEntry::from_entry("GET http://example.com/", &entry);
+
+        let mut bytes = Vec::new();
+        write_entry(&mut bytes, &stored).unwrap();
+        let mut reader = bytes.as_slice();
+        let read = read_entry(&mut reader).unwrap().expect("one entry");
+        assert!(read_entry(&mut reader).unwrap().is_none());
+
+        assert_eq!(read.key, "GET http://example.com/");
+        let restored = read.into_entry(&StalePolicy::default()).unwrap();
+        assert_eq!(restored.response.body(), entry.response.body());
+        assert_eq!(restored.response.headers(), entry.response.headers());
+        assert_eq!(restored.etag, entry.etag);
+        assert_eq!(restored.inserted_at, entry.inserted_at);
+        assert_eq!(restored.expires_at, entry.expires_at);
+        assert_eq!(restored.retain_until, entry.retain_until);
+        assert_eq!(restored.expiry_jitter_ms, -1500);
+    }
+
+    #[test]
+    fn corrupt_entries_are_rejected() {
+        let now = Utc::now();
+        let stored = StoredEntry::from_entry("GET http://example.com/", &entry(now));
+        let mut bytes = Vec::new();
+        write_entry(&mut bytes, &stored).unwrap();
+
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        assert!(read_entry(&mut bytes.as_slice()).is_err());
+        // bad magic
+        let garbage = [b'X'; STORED_ENTRY_HEADER_LEN];
+        assert!(read_entry(&mut &garbage[..]).is_err());
+    }
+
+    #[test]
+    fn out_of_range_timestamps_are_rejected() {
+        let mut stored = StoredEntry::from_entry("GET http://example.com/", &entry(Utc::now()));
+        stored.received_at_ms = i64::MAX;
+
+        assert!(stored.into_entry(&StalePolicy::default()).is_err());
+    }
+}
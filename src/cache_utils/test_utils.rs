@@ -0,0 +1,34 @@
+// imports
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+// local
+use super::{cache::MapValue, config::StalePolicy, entry::CachedEntry, freshness::Freshness};
+
+/// Response with `body` and `headers`, i.e. `[("cache-control", "max-age=60")]`
+pub fn response(body: &'static [u8], headers: &[(&'static str, &str)]) -> MapValue {
+    let mut res = MapValue::new(Bytes::from_static(body));
+    for (name, value) in headers {
+        res.headers_mut()
+            .insert(*name, value.parse().expect("valid header value"));
+    }
+
+    res
+}
+
+/// Entry for a response received and inserted at `now`, with the default stale policy
+pub fn entry_for(res: MapValue, now: DateTime<Utc>) -> CachedEntry {
+    let freshness = Freshness::from_response(&res, now).expect("storable");
+
+    CachedEntry::new(res, freshness, now, &StalePolicy::default())
+}
+
+/// Entry fresh for 60s from `now`, with an etag
+pub fn entry(now: DateTime<Utc>) -> CachedEntry {
+    entry_for(
+        response(
+            b"[]",
+            &[("cache-control", "max-age=60"), ("etag", "\"v1\"")],
+        ),
+        now,
+    )
+}
@@ -1,38 +1,115 @@
 // imports
-use std::sync::Arc;
+use std::{
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
 // local
-use super::cache::{HTTPCache, CACHE_TTL_SEC};
-
-/// 1) Iterate through all entries in the cache HashMap
-/// 1) Read timestamp value on the request (each entry is a request, key is URL)
-/// 1) If greater than 30 seconds, delete entry from cache
-/// 1) If at cache limit, remove oldest entry, (and insert current response in its place)
-pub fn purge_expired_cache_entries(cache: Arc<HTTPCache>) {
-    println!("\nPurging cache: ");
-    let mut map_reader = cache.lock_write().guard;
-    let init_map_size = map_reader.len();
-
-    map_reader.retain(|_, entry_mutex| {
-        // get a read lock for the entry
-        let entry = entry_mutex
-            .lock()
-            .expect("Poisoned mutex: checking for outdated entries.");
-
-        for (header_name, header_value) in entry.headers() {
-            if header_name != "date" {
-                continue;
+use super::cache::HTTPCache;
+
+/// 1) Iterate through all entries in the cache backend
+/// 1) Read the expiry stored on the entry (see `CachedEntry`), against the cache's clock
+/// 1) If the entry is past its expiry (and its retention for revalidation), delete entry from cache
+///
+/// Cache limit is enforced on insert, see `Cache::insert`
+///
+/// Returns the amount of entries removed
+pub fn purge_expired_cache_entries(cache: Arc<HTTPCache>) -> usize {
+    let dt_now = cache.now();
+
+    // each backend purges its own way, i.e. memory locks one shard at a time
+    let amt_purged = cache.purge_expired(dt_now);
+    if amt_purged > 0 {
+        println!(
+            "Purged {amt_purged} expired entries from {} cache",
+            cache.backend().name()
+        );
+    }
+
+    amt_purged
+}
+
+/// Spawn a thread that purges expired entries every `interval`
+///
+/// Runs for the lifetime of the process, so idle caches are cleaned as well.
+/// Cache stats are reported after each sweep, if there were lookups since the last one
+pub fn spawn_expiry_sweeper(cache: Arc<HTTPCache>, interval: Duration) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut prev_amt_lookups = 0;
+        loop {
+            thread::sleep(interval);
+            purge_expired_cache_entries(Arc::clone(&cache));
+
+            let stats = cache.stats();
+            let amt_lookups = stats.hits + stats.stale_hits + stats.misses;
+            if amt_lookups != prev_amt_lookups {
+                println!("Cache stats: {stats}");
+                prev_amt_lookups = amt_lookups;
             }
-            if let Ok(timestamp) =
-                chrono::DateTime::parse_from_rfc2822(header_value.to_str().unwrap())
-            {
-                let dt_now = chrono::Utc::now().timestamp();
-                let dt_response = timestamp.timestamp();
-                let diff = dt_now - dt_response;
-                return diff < CACHE_TTL_SEC;
-            };
         }
-        true
-    });
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cache_utils::{
+            cache::Lookup,
+            clock::MockClock,
+            entry::CachedEntry,
+            test_utils::{entry_for, response},
+        },
+        http_utils::constants::CACHE_STALE_RETENTION_SEC,
+    };
+    use chrono::{DateTime, Utc};
+
+    /// Entry fresh for 30s, kept for revalidation after that if it has an etag
+    fn entry(now: DateTime<Utc>, has_etag: bool) -> CachedEntry {
+        let headers: &[_] = if has_etag {
+            &[("cache-control", "max-age=30"), ("etag", "\"v1\"")]
+        } else {
+            &[("cache-control", "max-age=30")]
+        };
+
+        entry_for(response(b"[]", headers), now)
+    }
+
+    #[test]
+    fn entries_go_stale_then_are_purged_on_the_cache_clock() {
+        let clock = Arc::new(MockClock::default());
+        let cache = Arc::new(HTTPCache::new().with_clock(clock.clone()));
+        let now = cache.now();
+        cache.insert("GET http://a/".to_string(), entry(now, true));
+        cache.insert("GET http://b/".to_string(), entry(now, false));
+
+        // fresh
+        assert!(matches!(
+            cache.lookup("GET http://a/", cache.now()),
+            Lookup::Fresh(_)
+        ));
+        assert_eq!(purge_expired_cache_entries(Arc::clone(&cache)), 0);
+
+        // stale: the entry with an etag is retained for revalidation, the other one is purged
+        clock.advance(chrono::Duration::seconds(31));
+        assert!(matches!(
+            cache.lookup("GET http://a/", cache.now()),
+            Lookup::Stale(_)
+        ));
+        assert_eq!(purge_expired_cache_entries(Arc::clone(&cache)), 1);
+        assert!(matches!(
+            cache.lookup("GET http://b/", cache.now()),
+            Lookup::Miss
+        ));
+        assert_eq!(cache.len(), 1);
 
-    println!("new map size {} - init: {init_map_size}", map_reader.len())
+        // past its retention
+        clock.advance(chrono::Duration::seconds(CACHE_STALE_RETENTION_SEC));
+        assert_eq!(purge_expired_cache_entries(Arc::clone(&cache)), 1);
+        assert!(matches!(
+            cache.lookup("GET http://a/", cache.now()),
+            Lookup::Miss
+        ));
+        assert!(cache.is_empty());
+    }
 }
@@ -0,0 +1,119 @@
+// imports
+use http::{Method, Request};
+use std::{
+    fs,
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+};
+// local
+use super::cache::HTTPCache;
+use crate::http_utils::{
+    connection::fetch_and_insert,
+    errors::{fmt_error, RequestError, ResponseError, Result},
+    formatting::get_proxy_addr,
+};
+
+#[derive(Debug, Default)]
+/// Outcome of warming the cache from a list of target urls
+pub struct WarmReport {
+    /// Urls fetched from origin
+    pub amt_fetched: usize,
+    /// Urls already fresh in the cache, not fetched
+    pub amt_skipped: usize,
+    /// Urls that could not be fetched, with the error
+    pub failures: Vec<(String, String)>,
+}
+
+/// Read target urls from a file, one per line. Empty lines and lines starting with `#` are ignored
+pub fn read_warm_urls(path: &Path) -> Result<Vec<String>> {
+    let contents = fs::read_to_string(path)?;
+
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+/// Request for a target url, as a client would send it to the proxy
+pub fn warm_request(target_url: &str) -> Result<Request<Vec<u8>>> {
+    Request::builder()
+        .method(Method::GET)
+        .uri("/")
+        .header("host", get_proxy_addr())
+        .header("content-length", target_url.len())
+        .body(target_url.as_bytes().to_vec())
+        .map_err(|e| fmt_error(RequestError::InvalidTargetUrl, &e.to_string()))
+}
+
+/// Prefetch target urls into the cache, with at most `concurrency` requests to origin at a time
+///
+/// Uses the same path as a client cache miss (see `fetch_and_insert`),
+/// concurrent client requests on the same key share the fetch
+pub fn warm_cache(
+    cache: &Arc<HTTPCache>,
+    target_urls: Vec<String>,
+    concurrency: usize,
+) -> WarmReport {
+    let queue = Arc::new(Mutex::new(target_urls.into_iter()));
+    let report = Arc::new(Mutex::new(WarmReport::default()));
+
+    let workers: Vec<_> = (0..concurrency.max(1))
+        .map(|_| {
+            let queue = Arc::clone(&queue);
+            let report = Arc::clone(&report);
+            let cache = Arc::clone(cache);
+            thread::spawn(move || loop {
+                let target_url = match queue.lock().expect("Poisoned mutex: warm queue").next() {
+                    Some(target_url) => target_url,
+                    None => break,
+                };
+                let warmed = warm_url(&cache, &target_url);
+
+                let mut report = report.lock().expect("Poisoned mutex: warm report");
+                match warmed {
+                    Ok(true) => report.amt_fetched += 1,
+                    Ok(false) => report.amt_skipped += 1,
+                    Err(e) => report.failures.push((target_url, e.to_string())),
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().expect("Unable to join warm worker thread");
+    }
+
+    Arc::try_unwrap(report)
+        .expect("Warm workers are done")
+        .into_inner()
+        .expect("Poisoned mutex: warm report")
+}
+
+/// Fetch a single target url into the cache, returns false if it was already fresh
+fn warm_url(cache: &HTTPCache, target_url: &str) -> Result<bool> {
+    let req = warm_request(target_url)?;
+    let key = cache.key_for_request(&req)?;
+    // peek, warming is not a hit
+    let is_fresh = match cache.peek(&key) {
+        Some(entry) => !entry.is_expired(cache.now()),
+        None => false,
+    };
+    if is_fresh {
+        return Ok(false);
+    }
+
+    let res = cache
+        .in_flight()
+        .fetch(&key, || fetch_and_insert(&req, cache))?;
+    // error statuses are returned (and negatively cached) like any response, but nothing was warmed
+    if !res.status().is_success() {
+        return Err(fmt_error(
+            ResponseError::IncorrectResponse,
+            &format!("Origin responded {}", res.status()),
+        ));
+    }
+
+    Ok(true)
+}
@@ -0,0 +1,159 @@
+// libs
+use http::HeaderMap;
+// local
+use super::constants::DELTA_SECONDS_MAX;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+/// Parsed `Cache-Control` directives (RFC 9111 section 5.2), for both requests and responses
+///
+/// Directives that appear with an invalid value are treated as `0`,
+/// so the response is considered stale rather than fresh forever.
+pub struct CacheControl {
+    pub max_age: Option<i64>,
+    pub s_maxage: Option<i64>,
+    pub no_store: bool,
+    pub no_cache: bool,
+    pub private: bool,
+    pub public: bool,
+    pub must_revalidate: bool,
+    pub proxy_revalidate: bool,
+    pub immutable: bool,
+    /// RFC 5861: serve stale while revalidating in the background
+    pub stale_while_revalidate: Option<i64>,
+    /// RFC 5861: serve stale when origin fails
+    pub stale_if_error: Option<i64>,
+    /// Request: client accepts stale responses, up to this many seconds (any staleness without a value)
+    pub max_stale: Option<i64>,
+    /// Request: client wants responses that stay fresh for at least this many seconds
+    pub min_fresh: Option<i64>,
+    /// Request: client only wants a stored response, never one from origin
+    pub only_if_cached: bool,
+}
+
+impl CacheControl {
+    /// Parse all `cache-control` headers in the map, later directives override earlier ones
+    pub fn from_headers(header_map: &HeaderMap) -> Self {
+        let mut cache_control = Self::default();
+
+        for header_value in header_map.get_all("cache-control") {
+            let header_str = match header_value.to_str() {
+                Ok(s) => s,
+                Err(_) => continue,
+            };
+            for (name, value) in parse_directives(header_str) {
+                cache_control.set_directive(&name, value.as_deref());
+            }
+        }
+
+        cache_control
+    }
+
+    fn set_directive(&mut self, name: &str, value: Option<&str>) {
+        match name {
+            "max-age" => self.max_age = Some(parse_delta_seconds(value)),
+            "s-maxage" => self.s_maxage = Some(parse_delta_seconds(value)),
+            "no-store" => self.no_store = true,
+            "no-cache" => self.no_cache = true,
+            "private" => self.private = true,
+            "public" => self.public = true,
+            "must-revalidate" => self.must_revalidate = true,
+            "proxy-revalidate" => self.proxy_revalidate = true,
+            "immutable" => self.immutable = true,
+            "stale-while-revalidate" => {
+                self.stale_while_revalidate = Some(parse_delta_seconds(value))
+            }
+            "stale-if-error" => self.stale_if_error = Some(parse_delta_seconds(value)),
+            "max-stale" => {
+                self.max_stale = Some(match value {
+                    Some(_) => parse_delta_seconds(value),
+                    None => i64::MAX,
+                })
+            }
+            "min-fresh" => self.min_fresh = Some(parse_delta_seconds(value)),
+            "only-if-cached" => self.only_if_cached = true,
+            // unknown directives must be ignored
+            _ => {}
+        }
+    }
+}
+
+/// Split a `cache-control` header into lowercase directive names and their (unquoted) values
+pub fn parse_directives(header_str: &str) -> Vec<(String, Option<String>)> {
+    header_str
+        .split(',')
+        .map(str::trim)
+        .filter(|directive| !directive.is_empty())
+        .map(|directive| match directive.split_once('=') {
+            Some((name, value)) => (
+                name.trim().to_ascii_lowercase(),
+                Some(value.trim().trim_matches('"').to_string()),
+            ),
+            None => (directive.to_ascii_lowercase(), None),
+        })
+        .collect()
+}
+
+/// Parse a `delta-seconds` value, invalid or missing values are treated as `0`
+///
+/// Values too large to be represented are clamped to `DELTA_SECONDS_MAX`
+pub fn parse_delta_seconds(value: Option<&str>) -> i64 {
+    value
+        .map(str::trim)
+        .filter(|v| !v.is_empty() && v.bytes().all(|b| b.is_ascii_digit()))
+        .map(|v| {
+            v.parse::<u64>().map_or(DELTA_SECONDS_MAX, |v| {
+                v.min(DELTA_SECONDS_MAX as u64) as i64
+            })
+        })
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delta_seconds_are_clamped() {
+        assert_eq!(parse_delta_seconds(Some("60")), 60);
+        assert_eq!(
+            parse_delta_seconds(Some("10000000000000000")),
+            DELTA_SECONDS_MAX
+        );
+        // overflows u64, still a valid delta-seconds
+        assert_eq!(
+            parse_delta_seconds(Some("99999999999999999999999")),
+            DELTA_SECONDS_MAX
+        );
+        assert_eq!(parse_delta_seconds(Some("-5")), 0);
+        assert_eq!(parse_delta_seconds(Some("abc")), 0);
+        assert_eq!(parse_delta_seconds(None), 0);
+    }
+
+    fn from_header(value: &'static str) -> CacheControl {
+        let mut headers = HeaderMap::new();
+        headers.insert("cache-control", http::HeaderValue::from_static(value));
+        CacheControl::from_headers(&headers)
+    }
+
+    #[test]
+    fn request_directives() {
+        let cache_control = from_header("no-cache, max-age=30, min-fresh=10, only-if-cached");
+        assert!(cache_control.no_cache);
+        assert!(cache_control.only_if_cached);
+        assert_eq!(cache_control.max_age, Some(30));
+        assert_eq!(cache_control.min_fresh, Some(10));
+        assert_eq!(cache_control.max_stale, None);
+
+        assert_eq!(from_header("max-stale=60").max_stale, Some(60));
+        // any staleness without a value
+        assert_eq!(from_header("max-stale").max_stale, Some(i64::MAX));
+        assert_eq!(from_header("MAX-STALE").max_stale, Some(i64::MAX));
+        assert_eq!(
+            from_header("public"),
+            CacheControl {
+                public: true,
+                ..CacheControl::default()
+            }
+        );
+    }
+}
@@ -0,0 +1,239 @@
+// libs
+use chrono::{DateTime, Utc};
+use http::{HeaderMap, HeaderValue};
+// local
+use super::constants::CACHE_STATUS_NAME;
+use crate::cache_utils::entry::CachedEntry;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Short form of the cache status, sent as `x-cache`
+pub enum XCache {
+    /// Served from a fresh entry
+    Hit,
+    /// Fetched from origin, or revalidated with it
+    Miss,
+    /// Served from an expired entry
+    Stale,
+    /// Cache not used, as requested by the client (`no-store`)
+    Bypass,
+}
+
+impl XCache {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            XCache::Hit => "HIT",
+            XCache::Miss => "MISS",
+            XCache::Stale => "STALE",
+            XCache::Bypass => "BYPASS",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// How the proxy handled a request, sent to clients as `age`, `cache-status` (RFC 9211) and `x-cache`
+///
+/// Only added to the copy of the response sent out, stored entries are left as is
+pub struct CacheStatus {
+    pub x_cache: XCache,
+    /// Why the request went to origin (`uri-miss`, `stale`, `request`), None if served from the cache
+    pub fwd: Option<&'static str>,
+    /// Status of the response from origin
+    pub fwd_status: Option<u16>,
+    /// Response was stored in the cache
+    pub stored: bool,
+    /// Remaining freshness of the entry, negative once stale
+    pub ttl_sec: Option<i64>,
+    /// Age of the entry
+    pub age_sec: Option<i64>,
+}
+
+impl CacheStatus {
+    /// Served from a fresh entry
+    pub fn hit(entry: &CachedEntry, now: DateTime<Utc>) -> Self {
+        Self::from_entry(XCache::Hit, None, entry, now)
+    }
+    /// Served from an expired entry, without contacting origin
+    pub fn stale(entry: &CachedEntry, now: DateTime<Utc>) -> Self {
+        Self::from_entry(XCache::Stale, None, entry, now)
+    }
+    /// Served from an expired entry after revalidating it with origin failed (stale-if-error)
+    pub fn stale_if_error(entry: &CachedEntry, now: DateTime<Utc>) -> Self {
+        Self::from_entry(XCache::Stale, Some("stale"), entry, now)
+    }
+    /// Fetched from origin (`fwd` is the reason). `stored_entry` is the entry it was stored as, if any
+    pub fn forwarded(
+        fwd: &'static str,
+        fwd_status: Option<u16>,
+        stored_entry: Option<&CachedEntry>,
+        now: DateTime<Utc>,
+    ) -> Self {
+        match stored_entry {
+            Some(entry) => Self {
+                fwd_status,
+                stored: true,
+                ..Self::from_entry(XCache::Miss, Some(fwd), entry, now)
+            },
+            None => Self {
+                x_cache: XCache::Miss,
+                fwd: Some(fwd),
+                fwd_status,
+                stored: false,
+                ttl_sec: None,
+                age_sec: None,
+            },
+        }
+    }
+    /// Cache skipped on the client's request, the response is not stored
+    pub fn bypass(fwd_status: u16) -> Self {
+        Self {
+            x_cache: XCache::Bypass,
+            fwd: Some("request"),
+            fwd_status: Some(fwd_status),
+            stored: false,
+            ttl_sec: None,
+            age_sec: None,
+        }
+    }
+    fn from_entry(
+        x_cache: XCache,
+        fwd: Option<&'static str>,
+        entry: &CachedEntry,
+        now: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            x_cache,
+            fwd,
+            fwd_status: None,
+            stored: false,
+            ttl_sec: Some((entry.expires_at - now).num_seconds()),
+            age_sec: Some(entry.freshness.current_age(now)),
+        }
+    }
+    /// `cache-status` value for this proxy, i.e. `tcp-proxy; hit; ttl=25`
+    pub fn cache_status_value(&self) -> String {
+        let mut value = String::from(CACHE_STATUS_NAME);
+        match self.fwd {
+            Some(fwd) => value.push_str(&format!("; fwd={fwd}")),
+            None => value.push_str("; hit"),
+        }
+        if let Some(fwd_status) = self.fwd_status {
+            value.push_str(&format!("; fwd-status={fwd_status}"));
+        }
+        if self.stored {
+            value.push_str("; stored");
+        }
+        if let Some(ttl_sec) = self.ttl_sec {
+            value.push_str(&format!("; ttl={ttl_sec}"));
+        }
+
+        value
+    }
+    /// Add the headers to a response sent to the client
+    ///
+    /// `cache-status` is appended after the ones from upstream caches, `age` replaces the upstream's
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        if let Some(age_sec) = self.age_sec {
+            headers.insert("age", HeaderValue::from(age_sec.max(0)));
+        }
+        if let Ok(cache_status) = HeaderValue::from_str(&self.cache_status_value()) {
+            headers.append("cache-status", cache_status);
+        }
+        headers.insert("x-cache", HeaderValue::from_static(self.x_cache.as_str()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache_utils::test_utils::entry;
+
+    fn applied(cache_status: &CacheStatus) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("age", HeaderValue::from_static("100"));
+        headers.insert("cache-status", HeaderValue::from_static("upstream; hit"));
+        cache_status.apply(&mut headers);
+        headers
+    }
+
+    fn cache_statuses(headers: &HeaderMap) -> Vec<&str> {
+        headers
+            .get_all("cache-status")
+            .iter()
+            .map(|value| value.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn hit_headers() {
+        let now = Utc::now();
+        let headers = applied(&CacheStatus::hit(
+            &entry(now),
+            now + chrono::Duration::seconds(35),
+        ));
+
+        assert_eq!(headers["age"], "35");
+        assert_eq!(headers["x-cache"], "HIT");
+        assert_eq!(
+            cache_statuses(&headers),
+            [
+                "upstream; hit",
+                &format!("{CACHE_STATUS_NAME}; hit; ttl=25")
+            ]
+        );
+    }
+
+    #[test]
+    fn stale_headers() {
+        let now = Utc::now();
+        let entry = entry(now);
+        let later = now + chrono::Duration::seconds(70);
+
+        let headers = applied(&CacheStatus::stale(&entry, later));
+        assert_eq!(headers["x-cache"], "STALE");
+        assert_eq!(
+            cache_statuses(&headers)[1],
+            format!("{CACHE_STATUS_NAME}; hit; ttl=-10")
+        );
+
+        // origin was contacted, and failed
+        let headers = applied(&CacheStatus::stale_if_error(&entry, later));
+        assert_eq!(headers["x-cache"], "STALE");
+        assert_eq!(
+            cache_statuses(&headers)[1],
+            format!("{CACHE_STATUS_NAME}; fwd=stale; ttl=-10")
+        );
+    }
+
+    #[test]
+    fn forwarded_headers() {
+        let now = Utc::now();
+
+        let stored = applied(&CacheStatus::forwarded(
+            "uri-miss",
+            Some(200),
+            Some(&entry(now)),
+            now,
+        ));
+        assert_eq!(stored["age"], "0");
+        assert_eq!(stored["x-cache"], "MISS");
+        assert_eq!(
+            cache_statuses(&stored)[1],
+            format!("{CACHE_STATUS_NAME}; fwd=uri-miss; fwd-status=200; stored; ttl=60")
+        );
+
+        // the upstream's age is kept when the response has no entry
+        let not_stored = applied(&CacheStatus::forwarded("uri-miss", Some(404), None, now));
+        assert_eq!(not_stored["age"], "100");
+        assert_eq!(
+            cache_statuses(&not_stored)[1],
+            format!("{CACHE_STATUS_NAME}; fwd=uri-miss; fwd-status=404")
+        );
+
+        let bypass = applied(&CacheStatus::bypass(200));
+        assert_eq!(bypass["x-cache"], "BYPASS");
+        assert_eq!(
+            cache_statuses(&bypass)[1],
+            format!("{CACHE_STATUS_NAME}; fwd=request; fwd-status=200")
+        );
+    }
+}
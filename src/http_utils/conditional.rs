@@ -0,0 +1,274 @@
+// libs
+use http::{HeaderMap, HeaderValue, Request, Response, StatusCode};
+// local
+use super::formatting::{fnv1a_hash, parse_http_date};
+
+/// Request headers used by clients to make a request conditional
+pub const CONDITIONAL_HEADERS: [&str; 4] = [
+    "if-none-match",
+    "if-modified-since",
+    "if-match",
+    "if-unmodified-since",
+];
+/// Headers that describe the stored body, and must not be replaced by a 304 (RFC 9111 section 3.2)
+const BODY_HEADERS: [&str; 4] = [
+    "content-length",
+    "content-encoding",
+    "transfer-encoding",
+    "content-range",
+];
+
+/// Headers kept on a `304 Not Modified` sent to clients (RFC 9110 section 15.4.5)
+const NOT_MODIFIED_HEADERS: [&str; 7] = [
+    "cache-control",
+    "content-location",
+    "date",
+    "etag",
+    "expires",
+    "last-modified",
+    "vary",
+];
+
+/// Copy a request
+///
+/// `http::Request` is not `Clone` (because of its extensions), so only method, uri, version, headers and body are copied
+pub fn copy_request(req: &Request<Vec<u8>>) -> Request<Vec<u8>> {
+    let mut new_req = Request::new(req.body().clone());
+    *new_req.method_mut() = req.method().clone();
+    *new_req.uri_mut() = req.uri().clone();
+    *new_req.version_mut() = req.version();
+    *new_req.headers_mut() = req.headers().clone();
+
+    new_req
+}
+
+/// Copy of the request without any of the client's conditional headers.
+///
+/// Used when the proxy fetches a full response, so origin never answers a plain miss with a 304
+pub fn unconditional_request(req: &Request<Vec<u8>>) -> Request<Vec<u8>> {
+    let mut new_req = copy_request(req);
+    for header_name in CONDITIONAL_HEADERS {
+        new_req.headers_mut().remove(header_name);
+    }
+
+    new_req
+}
+
+/// Copy of the request, validated against a stored response:
+/// `If-None-Match` from its `ETag`, `If-Modified-Since` from its `Last-Modified`
+pub fn conditional_request<T>(req: &Request<Vec<u8>>, stored: &Response<T>) -> Request<Vec<u8>> {
+    let mut new_req = unconditional_request(req);
+    let stored_headers = stored.headers();
+
+    if let Some(etag) = stored_headers.get("etag") {
+        new_req.headers_mut().insert("if-none-match", etag.clone());
+    }
+    if let Some(last_modified) = stored_headers.get("last-modified") {
+        new_req
+            .headers_mut()
+            .insert("if-modified-since", last_modified.clone());
+    }
+
+    new_req
+}
+
+/// Stored response has a validator the origin can be asked about
+pub fn has_validators(header_map: &HeaderMap) -> bool {
+    header_map.contains_key("etag") || header_map.contains_key("last-modified")
+}
+
+/// Update a stored response with the headers of a `304 Not Modified` (RFC 9111 section 4.3.4)
+///
+/// Status and body are kept, headers from the 304 replace the stored ones
+pub fn merge_not_modified<T: Clone, U>(
+    stored: &Response<T>,
+    not_modified: &Response<U>,
+) -> Response<T> {
+    let mut merged = Response::new(stored.body().clone());
+    *merged.status_mut() = stored.status();
+    *merged.version_mut() = stored.version();
+    *merged.headers_mut() = stored.headers().clone();
+
+    for header_name in not_modified.headers().keys() {
+        if BODY_HEADERS.contains(&header_name.as_str()) {
+            continue;
+        }
+        merged.headers_mut().remove(header_name);
+        for header_value in not_modified.headers().get_all(header_name) {
+            merged
+                .headers_mut()
+                .append(header_name.clone(), header_value.clone());
+        }
+    }
+
+    merged
+}
+
+/// The response's `etag`, or a strong one generated from its body if the upstream sent none
+pub fn response_etag<T: AsRef<[u8]>>(res: &Response<T>) -> HeaderValue {
+    match res.headers().get("etag") {
+        Some(etag) => etag.clone(),
+        None => generate_etag(res.body().as_ref()),
+    }
+}
+
+/// Strong `etag` derived from the body: FNV-1a hash and length
+pub fn generate_etag(body: &[u8]) -> HeaderValue {
+    let hash = fnv1a_hash(&[body]);
+
+    HeaderValue::from_str(&format!("\"{hash:016x}-{:x}\"", body.len()))
+        .expect("Generated etag is a valid header value")
+}
+
+/// Evaluate the client's `if-none-match`/`if-modified-since` against a response (RFC 9110 section 13.2.2)
+///
+/// `if-modified-since` is only used when the request has no `if-none-match`
+pub fn is_not_modified<T>(req_headers: &HeaderMap, res: &Response<T>, etag: &HeaderValue) -> bool {
+    if let Some(if_none_match) = req_headers.get("if-none-match") {
+        return if_none_match
+            .to_str()
+            .map(|tags| {
+                tags.split(',')
+                    .map(str::trim)
+                    .any(|tag| tag == "*" || weak_eq(tag.as_bytes(), etag.as_bytes()))
+            })
+            .unwrap_or(false);
+    }
+
+    let if_modified_since = req_headers
+        .get("if-modified-since")
+        .and_then(parse_http_date);
+    let last_modified = res.headers().get("last-modified").and_then(parse_http_date);
+    match (if_modified_since, last_modified) {
+        (Some(if_modified_since), Some(last_modified)) => last_modified <= if_modified_since,
+        _ => false,
+    }
+}
+
+/// Weak comparison of two entity tags, ignores the `W/` prefix
+fn weak_eq(tag_a: &[u8], tag_b: &[u8]) -> bool {
+    tag_a.strip_prefix(b"W/").unwrap_or(tag_a) == tag_b.strip_prefix(b"W/").unwrap_or(tag_b)
+}
+
+/// Build the `304 Not Modified` for a response, without a body
+pub fn not_modified_response<T, U: Default>(res: &Response<T>, etag: &HeaderValue) -> Response<U> {
+    let mut not_modified = Response::new(U::default());
+    *not_modified.status_mut() = StatusCode::NOT_MODIFIED;
+    *not_modified.version_mut() = res.version();
+
+    for header_name in NOT_MODIFIED_HEADERS {
+        for header_value in res.headers().get_all(header_name) {
+            not_modified
+                .headers_mut()
+                .append(header_name, header_value.clone());
+        }
+    }
+    not_modified.headers_mut().insert("etag", etag.clone());
+
+    not_modified
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(headers: &[(&'static str, &str)]) -> HeaderMap {
+        headers
+            .iter()
+            .map(|(name, value)| {
+                (
+                    http::header::HeaderName::from_static(name),
+                    HeaderValue::from_str(value).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    fn response(res_headers: &[(&'static str, &str)]) -> Response<Vec<u8>> {
+        let mut res = Response::new(b"[]".to_vec());
+        *res.headers_mut() = headers(res_headers);
+        res
+    }
+
+    #[test]
+    fn if_none_match_lists_and_star() {
+        let res = response(&[]);
+        let etag = HeaderValue::from_static("\"v2\"");
+
+        let listed = headers(&[("if-none-match", "\"v1\", \"v2\"")]);
+        assert!(is_not_modified(&listed, &res, &etag));
+        let other = headers(&[("if-none-match", "\"v1\", \"v3\"")]);
+        assert!(!is_not_modified(&other, &res, &etag));
+        let star = headers(&[("if-none-match", "*")]);
+        assert!(is_not_modified(&star, &res, &etag));
+    }
+
+    #[test]
+    fn if_none_match_uses_weak_comparison() {
+        let res = response(&[]);
+
+        let weak_request = headers(&[("if-none-match", "W/\"v1\"")]);
+        assert!(is_not_modified(
+            &weak_request,
+            &res,
+            &HeaderValue::from_static("\"v1\"")
+        ));
+        let strong_request = headers(&[("if-none-match", "\"v1\"")]);
+        assert!(is_not_modified(
+            &strong_request,
+            &res,
+            &HeaderValue::from_static("W/\"v1\"")
+        ));
+    }
+
+    #[test]
+    fn if_modified_since_only_without_if_none_match() {
+        let res = response(&[("last-modified", "Mon, 01 Jan 2024 00:00:00 GMT")]);
+        let etag = HeaderValue::from_static("\"v1\"");
+
+        let later = headers(&[("if-modified-since", "Tue, 02 Jan 2024 00:00:00 GMT")]);
+        assert!(is_not_modified(&later, &res, &etag));
+        let earlier = headers(&[("if-modified-since", "Sun, 31 Dec 2023 00:00:00 GMT")]);
+        assert!(!is_not_modified(&earlier, &res, &etag));
+
+        // a non matching `if-none-match` wins over a matching `if-modified-since`
+        let both = headers(&[
+            ("if-none-match", "\"v2\""),
+            ("if-modified-since", "Tue, 02 Jan 2024 00:00:00 GMT"),
+        ]);
+        assert!(!is_not_modified(&both, &res, &etag));
+    }
+
+    #[test]
+    fn generated_etag_is_stable() {
+        assert_eq!(generate_etag(b"[1, 2]"), generate_etag(b"[1, 2]"));
+        assert_ne!(generate_etag(b"[1, 2]"), generate_etag(b"[2, 1]"));
+        assert!(generate_etag(b"").to_str().unwrap().starts_with('"'));
+    }
+
+    #[test]
+    fn merge_not_modified_keeps_body_headers() {
+        let mut stored = response(&[
+            ("content-length", "2"),
+            ("content-encoding", "gzip"),
+            ("cache-control", "max-age=60"),
+            ("etag", "\"v1\""),
+        ]);
+        *stored.status_mut() = StatusCode::OK;
+        let mut not_modified = Response::new(Vec::<u8>::new());
+        *not_modified.status_mut() = StatusCode::NOT_MODIFIED;
+        *not_modified.headers_mut() = headers(&[
+            ("content-length", "0"),
+            ("content-encoding", "identity"),
+            ("cache-control", "max-age=120"),
+        ]);
+
+        let merged = merge_not_modified(&stored, &not_modified);
+        assert_eq!(merged.status(), StatusCode::OK);
+        assert_eq!(merged.body(), b"[]");
+        assert_eq!(merged.headers()["content-length"], "2");
+        assert_eq!(merged.headers()["content-encoding"], "gzip");
+        assert_eq!(merged.headers()["cache-control"], "max-age=120");
+        assert_eq!(merged.headers()["etag"], "\"v1\"");
+    }
+}
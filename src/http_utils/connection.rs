@@ -1,15 +1,29 @@
 // libs
-use http::{HeaderMap, Response};
-use std::{io::Write, net::TcpStream, sync::Arc};
+use http::{HeaderMap, HeaderValue, Response, StatusCode};
+use std::{io::Write, net::TcpStream, sync::Arc, thread};
 // local
 use super::{
+    cache_control::CacheControl,
+    cache_status::CacheStatus,
+    conditional::{
+        conditional_request, copy_request, is_not_modified, merge_not_modified,
+        not_modified_response, response_etag, unconditional_request,
+    },
     constants::*,
     errors::*,
     formatting::get_origin_addr,
     request::{get_parsed_request, write_req_to_origin},
-    response::{read_res_from_origin, write_response_to_client},
+    response::{clone_response, read_res_from_origin, write_error_res, write_response_to_client},
+};
+use crate::cache_utils::{
+    cache::{HTTPCache, Lookup, MapValue, ResBody},
+    coalesce::Revalidation,
+    compression::{content_encoding, decode_for_client, etag_for_client, vary_on_accept_encoding},
+    entry::CachedEntry,
+    freshness::Freshness,
+    key::CacheKey,
+    purge::{is_authorized, is_purge_request, PurgeScope},
 };
-use crate::cache_utils::cache::{CacheWriteLock, HTTPCache};
 
 pub fn check_body_len(header_map: &http::HeaderMap) -> Result<usize> {
     let header_value = header_map.get("content-length");
@@ -36,13 +50,16 @@ pub fn check_body_len(header_map: &http::HeaderMap) -> Result<usize> {
 ///
 /// 1) Attempt to write to origin
 /// 2) Validate and format the response from [destination > origin > proxy]
+///
+/// Error responses with a status in `negative_statuses` are returned, so they can be cached
 pub fn forward_request_and_return_response(
     parsed_req: &http::Request<Vec<u8>>,
+    negative_statuses: &[u16],
 ) -> Result<Response<Vec<u8>>> {
-    let mut proxy_origin_stream = TcpStream::connect(&get_origin_addr())?;
+    let mut proxy_origin_stream = TcpStream::connect(get_origin_addr())?;
     // 1) write to origin
     // TODO: propagate error to client http response
-    if let Err(err) = write_req_to_origin(&mut proxy_origin_stream, &parsed_req) {
+    if let Err(err) = write_req_to_origin(&mut proxy_origin_stream, parsed_req) {
         return Err(fmt_error(
             RequestError::ConnectionError(err),
             "Writing to origin:",
@@ -50,11 +67,15 @@ pub fn forward_request_and_return_response(
     };
 
     // 2.a) Read the response from origin
-    let res_from_origin = read_res_from_origin(&mut proxy_origin_stream).or_else(|err| Err(err))?;
+    let res_from_origin = read_res_from_origin(&mut proxy_origin_stream)?;
 
-    // 2.b) validate response, proceed if 200 error code
+    // 2.b) validate response, proceed if 200 error code (or 304 for conditional requests),
+    //      or an error status that is negatively cached
     let response_status = res_from_origin.status().as_u16();
-    if response_status != 200 {
+    if response_status != 200
+        && response_status != 304
+        && !negative_statuses.contains(&response_status)
+    {
         return Err(fmt_error(
             ResponseError::IncorrectResponse,
             &response_status.to_string(),
@@ -64,6 +85,240 @@ pub fn forward_request_and_return_response(
     Ok(res_from_origin)
 }
 
+/// Add a response from origin to the cache, if the upstream allows it
+///
+/// Error responses with a negatively cached status are stored with the short negative ttl.
+/// `stale_entry` is the entry a `304 Not Modified` refreshed, `res` then has its stored (maybe compressed) body
+///
+/// Returns the response
+fn insert_response(
+    parsed_req: &http::Request<Vec<u8>>,
+    res: MapValue,
+    stale_entry: Option<&CachedEntry>,
+    cache: &HTTPCache,
+) -> Result<MapValue> {
+    let dt_received = cache.now();
+    let config = cache.config();
+    let freshness = match Freshness::from_response(&res, dt_received) {
+        Some(freshness) if config.is_negative_status(res.status().as_u16()) => {
+            println!("origin returned {}... caching briefly", res.status());
+            Some(freshness.negative(config.negative_ttl_sec))
+        }
+        Some(freshness) if res.status() == StatusCode::OK => Some(freshness),
+        _ => None,
+    };
+    match freshness {
+        Some(freshness) => {
+            // Insert, the body is shared with the cached copy
+            let target_url = CacheKey::from_request(parsed_req)?.url;
+            let stale_policy = config.stale_policy(&target_url);
+            let mut new_entry =
+                CachedEntry::new(clone_response(&res), freshness, dt_received, stale_policy)
+                    .with_expiry_jitter(config.expiry_jitter_ms(freshness.lifetime_sec));
+            if let Some(compression) = config.compression {
+                new_entry = new_entry.with_compression(compression);
+            }
+            if let Some(stale_entry) = stale_entry {
+                new_entry = new_entry.with_revalidated(stale_entry);
+            }
+            cache.insert_req(parsed_req, new_entry)?;
+        }
+        None => println!("response is not storable (no-store/private)... skipping cache"),
+    }
+
+    Ok(res)
+}
+
+/// Fetch from origin and add the response to the cache, if the upstream allows it
+///
+/// Returns the response from origin
+pub fn fetch_and_insert(
+    parsed_req: &http::Request<Vec<u8>>,
+    cache: &HTTPCache,
+) -> Result<MapValue> {
+    println!("cache miss... making request to origin... ");
+    // the client's own validators are not forwarded, the proxy needs the full response
+    let origin_req = unconditional_request(parsed_req);
+    let res_from_origin =
+        forward_request_and_return_response(&origin_req, &cache.config().negative_statuses)?
+            .map(ResBody::from);
+
+    insert_response(parsed_req, res_from_origin, None, cache)
+}
+
+/// Revalidate a stale entry with origin, using its `etag`/`last-modified`
+///
+/// On `304 Not Modified` the stored body is kept and its metadata refreshed,
+/// otherwise the new response replaces the entry
+///
+/// Returns the response, and whether origin answered `304 Not Modified`
+fn revalidate_and_insert(
+    parsed_req: &http::Request<Vec<u8>>,
+    stale_entry: &CachedEntry,
+    cache: &HTTPCache,
+) -> Result<Revalidation> {
+    println!("stale entry... revalidating with origin... ");
+    let origin_req = conditional_request(parsed_req, &stale_entry.response);
+    let res_from_origin =
+        forward_request_and_return_response(&origin_req, &cache.config().negative_statuses)?
+            .map(ResBody::from);
+
+    // stale-if-error: an origin error must not replace an entry that can still be served instead
+    let response_status = res_from_origin.status();
+    if response_status.is_server_error() && stale_entry.can_serve_stale_if_error(cache.now()) {
+        return Err(fmt_error(
+            ResponseError::IncorrectResponse,
+            &response_status.to_string(),
+        ));
+    }
+    if response_status != StatusCode::NOT_MODIFIED {
+        let res = insert_response(parsed_req, res_from_origin, None, cache)?;
+        return Ok((res, false));
+    }
+
+    println!("not modified... refreshing cached entry");
+    let refreshed_res = merge_not_modified(&stale_entry.response, &res_from_origin);
+    let res = insert_response(parsed_req, refreshed_res, Some(stale_entry), cache)?;
+
+    Ok((res, true))
+}
+
+/// Revalidate an entry on a separate thread, the client is served the cached entry meanwhile
+///
+/// Used for stale entries (stale-while-revalidate) and hot entries about to expire (refresh-ahead).
+/// Nothing is spawned if the entry is already being fetched
+fn spawn_background_revalidation(
+    parsed_req: &http::Request<Vec<u8>>,
+    query_key: &str,
+    cached_entry: &Arc<CachedEntry>,
+    cache: &Arc<HTTPCache>,
+) {
+    if cache.revalidations().is_in_flight(query_key) {
+        return;
+    }
+
+    let parsed_req = copy_request(parsed_req);
+    let query_key = query_key.to_string();
+    let stale_entry = Arc::clone(cached_entry);
+    let cache = Arc::clone(cache);
+    thread::spawn(move || {
+        let revalidation = cache.revalidations().fetch(&query_key, || {
+            revalidate_and_insert(&parsed_req, &stale_entry, &cache)
+        });
+        if let Err(e) = revalidation {
+            eprintln!("background revalidation failed: {e}");
+        }
+    });
+}
+
+/// Copy of a stale response sent to clients, marked with a `warning`
+///
+/// The stored response is left as is
+fn stale_response(res: &MapValue, warning: &'static str) -> MapValue {
+    let mut stale_res = clone_response(res);
+    stale_res
+        .headers_mut()
+        .insert("warning", HeaderValue::from_static(warning));
+
+    stale_res
+}
+
+/// Cache status of a response fetched from origin for `parsed_req`
+///
+/// The response was stored if the cache holds an entry for the request inserted since `fetched_since`
+fn forwarded_status(
+    cache: &HTTPCache,
+    parsed_req: &http::Request<Vec<u8>>,
+    fwd: &'static str,
+    fwd_status: Option<u16>,
+    fetched_since: chrono::DateTime<chrono::Utc>,
+) -> CacheStatus {
+    // the key is built again, the response's `Vary` may have changed it
+    let stored_entry = cache
+        .key_for_request(parsed_req)
+        .ok()
+        .and_then(|key| cache.peek(&key))
+        .filter(|entry| entry.inserted_at >= fetched_since);
+
+    CacheStatus::forwarded(fwd, fwd_status, stored_entry.as_deref(), cache.now())
+}
+
+/// Write a response to the client, honoring the client's conditional headers
+///
+/// 1) `304 Not Modified` without a body if the client's validators match
+/// 2) otherwise the full response, with its `etag` (added if the upstream sent none),
+///    decompressed if the client does not accept its encoding
+///
+/// `etag` is the uncompressed body's, bodies `compressed` by the proxy get their own (see `etag_for_client`)
+///
+/// Both get the `age`, `cache-status` and `x-cache` headers, the stored response is left as is
+fn write_response_for_request(
+    stream: &mut TcpStream,
+    parsed_req: &http::Request<Vec<u8>>,
+    res: &MapValue,
+    etag: &HeaderValue,
+    compressed: bool,
+    cache_status: &CacheStatus,
+) -> Result<()> {
+    let etag = etag_for_client(parsed_req.headers(), res, etag, compressed);
+    let mut res_out: MapValue = if res.status() == StatusCode::OK
+        && is_not_modified(parsed_req.headers(), res, &etag)
+    {
+        println!("client validators match... not modified");
+        not_modified_response(res, &etag)
+    } else {
+        // compressed bodies are decompressed for clients that do not accept their encoding
+        let mut res_out = match decode_for_client(parsed_req.headers(), res)? {
+            Some(decoded_res) => decoded_res,
+            None => clone_response(res),
+        };
+        if res.status() == StatusCode::OK && (compressed || !res.headers().contains_key("etag")) {
+            res_out.headers_mut().insert("etag", etag);
+        }
+        res_out
+    };
+    if content_encoding(res.headers()).is_some() {
+        vary_on_accept_encoding(res_out.headers_mut());
+    }
+    cache_status.apply(res_out.headers_mut());
+
+    write_response_to_client(stream, &res_out)
+}
+
+/// Handle a `PURGE` request: remove the matching entries, respond with the amount removed
+///
+/// i.e. `{"purged":3}`. Requests without the purge token get a `401`, invalid targets a `400`
+fn handle_purge_request(
+    stream: &mut TcpStream,
+    parsed_req: &http::Request<Vec<u8>>,
+    cache: &HTTPCache,
+) -> Result<()> {
+    if !is_authorized(parsed_req, cache.config()) {
+        let err = fmt_error(RequestError::Unauthorized, "Purge");
+        write_error_res(&err, stream, 401);
+        return Ok(());
+    }
+    let scope = match PurgeScope::from_request(parsed_req) {
+        Ok(scope) => scope,
+        Err(e) => {
+            write_error_res(&e, stream, 400);
+            return Ok(());
+        }
+    };
+
+    let amt_purged = cache.purge(&scope);
+    println!("purged {amt_purged} entries: {scope:?}");
+    let body = format!("{{\"purged\":{amt_purged}}}").into_bytes();
+    let res = Response::builder()
+        .status(StatusCode::OK)
+        .version(http::Version::HTTP_11)
+        .header("content-type", "application/json")
+        .header("content-length", body.len())
+        .body(body)?;
+
+    write_response_to_client(stream, &res)
+}
+
 /// Handle the tcp connection between client and proxy
 ///
 /// 1) forward request to origin
@@ -71,15 +326,18 @@ pub fn forward_request_and_return_response(
 /// 3) write back to client
 ///
 /// Handle error responses to client here
-pub fn handle_client_proxy_connection<'a>(
+pub fn handle_client_proxy_connection(
     mut client_proxy_connection: TcpStream,
-    cache: &'a Arc<HTTPCache>,
+    cache: &Arc<HTTPCache>,
 ) -> Result<()> {
     ////////////////////////////////////////////
     // 1) parse http request
 
     // TODO: propagate error to client http response
     let parsed_req = get_parsed_request(&mut client_proxy_connection)?;
+    if is_purge_request(&parsed_req) {
+        return handle_purge_request(&mut client_proxy_connection, &parsed_req, cache);
+    }
 
     // 1) parse http request
     ////////////////////////////////////////////
@@ -88,32 +346,175 @@ pub fn handle_client_proxy_connection<'a>(
     // 2) check cache
 
     // return early if we have an entry in the cache
-    let query_key = String::from_utf8(parsed_req.body().to_vec()).unwrap();
+    // key: method, canonical target url, and the request headers the stored response varies on
+    let query_key = cache.key_for_request(&parsed_req)?;
+
+    // client directives: no-cache, no-store, max-age, max-stale, min-fresh, only-if-cached
+    let req_cache_control = CacheControl::from_headers(parsed_req.headers());
+
+    // no-store: bypass the cache, the response is not stored either
+    if req_cache_control.no_store {
+        println!("client sent no-store... bypassing cache");
+        let origin_req = unconditional_request(&parsed_req);
+        let res_from_origin =
+            forward_request_and_return_response(&origin_req, &cache.config().negative_statuses)?
+                .map(ResBody::from);
+        let etag = response_etag(&res_from_origin);
+
+        return write_response_for_request(
+            &mut client_proxy_connection,
+            &parsed_req,
+            &res_from_origin,
+            &etag,
+            false,
+            &CacheStatus::bypass(res_from_origin.status().as_u16()),
+        );
+    }
 
-    let lock_r = cache.lock_read();
-    match lock_r.get(&query_key) {
-        Some(entry_mutex) => {
-            write_response_to_client(&mut client_proxy_connection, entry_mutex)?;
-            drop(lock_r);
+    // the shard lock is only held for the lookup, the client write happens without it
+    let dt_now = cache.now();
+    match cache.lookup(&query_key, dt_now) {
+        Lookup::Fresh(entry) | Lookup::Stale(entry)
+            if entry.satisfies(&req_cache_control, dt_now) =>
+        {
+            // expired entries only get here if the client allows it (max-stale)
+            if entry.is_expired(dt_now) {
+                let stale_res = stale_response(&entry.response, WARNING_STALE);
+                write_response_for_request(
+                    &mut client_proxy_connection,
+                    &parsed_req,
+                    &stale_res,
+                    &entry.etag,
+                    entry.uncompressed_body_len.is_some(),
+                    &CacheStatus::stale(&entry, dt_now),
+                )?;
+            } else {
+                // refresh-ahead: hot entries are refetched before they expire, off the client's path
+                let config = cache.config();
+                if entry.needs_refresh_ahead(
+                    dt_now,
+                    config.refresh_ahead_min_hits,
+                    config.refresh_ahead_percent,
+                ) {
+                    println!("hot entry nearing expiry... refreshing it in the background");
+                    spawn_background_revalidation(&parsed_req, &query_key, &entry, cache);
+                }
+                write_response_for_request(
+                    &mut client_proxy_connection,
+                    &parsed_req,
+                    &entry.response,
+                    &entry.etag,
+                    entry.uncompressed_body_len.is_some(),
+                    &CacheStatus::hit(&entry, dt_now),
+                )?;
+            }
         }
-        None => {
-            // If the cache didnt return a value-
-            //     0) drop the read lock
-            //     1) query the external source (fwd to origin first)
-            //     2) add to cache
-            //     3) send the http response with payload back to the client
-            drop(lock_r);
+        // only-if-cached: no stored response the client accepts, origin must not be contacted
+        _ if req_cache_control.only_if_cached => {
+            println!("client sent only-if-cached... no suitable cached response");
+            let err = failure::err_msg("only-if-cached: no suitable cached response");
+            write_error_res(&err, &mut client_proxy_connection, 504);
+        }
+        // not if the client's no-cache, max-age or min-fresh rule the entry out
+        Lookup::Stale(stale_entry)
+            if stale_entry.meets_request_limits(&req_cache_control, dt_now)
+                && stale_entry.can_serve_stale_while_revalidate(dt_now) =>
+        {
+            // stale-while-revalidate: serve the stale entry, refresh it in the background
+            println!("stale entry... serving it while revalidating in the background");
+            spawn_background_revalidation(&parsed_req, &query_key, &stale_entry, cache);
 
-            // TODO: propagate error to http response
-            println!("cache miss... making request to origin... ");
-            let res_from_origin = forward_request_and_return_response(&parsed_req)?;
+            let stale_res = stale_response(&stale_entry.response, WARNING_STALE);
+            write_response_for_request(
+                &mut client_proxy_connection,
+                &parsed_req,
+                &stale_res,
+                &stale_entry.etag,
+                stale_entry.uncompressed_body_len.is_some(),
+                &CacheStatus::stale(&stale_entry, dt_now),
+            )?;
+        }
+        Lookup::Fresh(stale_entry) | Lookup::Stale(stale_entry) => {
+            // Entry is expired (or the client requires revalidation): ask origin if it changed,
+            // concurrent requests on the same key share the revalidation
+            let revalidation = cache.revalidations().fetch(&query_key, || {
+                revalidate_and_insert(&parsed_req, &stale_entry, cache)
+            });
+
+            let compressed = stale_entry.uncompressed_body_len.is_some();
+            let (res, etag, compressed, cache_status) = match revalidation {
+                Ok(revalidated) => {
+                    let (res_from_origin, is_not_modified) = revalidated.as_ref();
+                    // not modified: the stored body is kept, so is the etag generated before compressing it
+                    let etag = if *is_not_modified {
+                        stale_entry.etag.clone()
+                    } else {
+                        response_etag(res_from_origin)
+                    };
+                    // fresh entries only get here if the client requires revalidation
+                    let fwd = if stale_entry.is_expired(dt_now) {
+                        "stale"
+                    } else {
+                        "request"
+                    };
+                    let cache_status = forwarded_status(cache, &parsed_req, fwd, None, dt_now);
+                    let compressed = compressed && *is_not_modified;
+                    (
+                        clone_response(res_from_origin),
+                        etag,
+                        compressed,
+                        cache_status,
+                    )
+                }
+                // stale-if-error: origin failed, serve the stale entry instead
+                Err(e) if stale_entry.can_serve_stale_if_error(cache.now()) => {
+                    eprintln!("revalidation failed, serving stale entry: {e}");
+                    let stale_res =
+                        stale_response(&stale_entry.response, WARNING_REVALIDATION_FAILED);
+                    let cache_status = CacheStatus::stale_if_error(&stale_entry, cache.now());
+                    (
+                        stale_res,
+                        stale_entry.etag.clone(),
+                        compressed,
+                        cache_status,
+                    )
+                }
+                Err(e) => return Err(e),
+            };
 
-            // Insert
-            let mut lock_w = cache.lock_write();
+            write_response_for_request(
+                &mut client_proxy_connection,
+                &parsed_req,
+                &res,
+                &etag,
+                compressed,
+                &cache_status,
+            )?;
+        }
+        Lookup::Miss => {
+            // If the cache didnt return a value (missing or expired)-
+            //     0) remove the entry if it expired
+            //     1) query the external source (fwd to origin first),
+            //        concurrent misses on the same key share a single fetch
+            //     2) add to cache, if the upstream allows it
+            //     3) send the http response with payload back to the client
+            cache.remove_expired(&query_key, dt_now);
 
-            let entry_mutex = CacheWriteLock::insert_req(&mut lock_w, parsed_req, res_from_origin);
+            // TODO: propagate error to http response
+            let res_from_origin = cache
+                .in_flight()
+                .fetch(&query_key, || fetch_and_insert(&parsed_req, cache))?;
 
-            write_response_to_client(&mut client_proxy_connection, entry_mutex)?;
+            let etag = response_etag(&res_from_origin);
+            let fwd_status = Some(res_from_origin.status().as_u16());
+            write_response_for_request(
+                &mut client_proxy_connection,
+                &parsed_req,
+                &res_from_origin,
+                &etag,
+                false,
+                &forwarded_status(cache, &parsed_req, "uri-miss", fwd_status, dt_now),
+            )?;
         }
     };
     // 2) check cache
@@ -130,25 +531,147 @@ pub fn write_to_stream(
     stream: &mut TcpStream,
     status_str: String,
     header_map: &HeaderMap,
-    http_body: &Vec<u8>,
+    http_body: &[u8],
 ) -> Result<()> {
     //res
-    stream.write(&status_str.into_bytes())?;
-    stream.write(b"\r\n")?;
+    stream.write_all(status_str.as_bytes())?;
+    stream.write_all(b"\r\n")?;
 
     // TODO: propagate error to http response
     // write header to stream
     for (header_name, header_value) in header_map {
-        stream.write(&format!("{}: ", header_name).as_bytes())?;
-        stream.write(header_value.as_bytes())?;
-        stream.write(b"\r\n")?;
+        stream.write_all(format!("{}: ", header_name).as_bytes())?;
+        stream.write_all(header_value.as_bytes())?;
+        stream.write_all(b"\r\n")?;
     }
-    stream.write(b"\r\n")?;
+    stream.write_all(b"\r\n")?;
 
     // write body to stream
-    if http_body.len() > 0 {
-        stream.write(http_body)?;
+    if !http_body.is_empty() {
+        stream.write_all(http_body)?;
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_utils::conditional::generate_etag;
+    use std::{
+        io::Read,
+        net::TcpListener,
+        sync::Mutex,
+        thread::JoinHandle,
+        time::{Duration, Instant},
+    };
+
+    /// Origin is always at `get_origin_addr`, tests talking to it take turns
+    static ORIGIN_LOCK: Mutex<()> = Mutex::new(());
+
+    const TARGET_URL: &[u8] = b"http://example.com/items";
+    /// The target url is sent as the body, like `curl localhost:8081 -d <url> -X GET`
+    const REQUEST: &[u8] =
+        b"GET / HTTP/1.1\r\nhost: localhost:8081\r\ncontent-length: 24\r\n\r\nhttp://example.com/items";
+
+    /// Stand-in origin answering one connection per response, in order
+    fn spawn_origin(responses: Vec<&'static str>) -> JoinHandle<()> {
+        let listener = TcpListener::bind(get_origin_addr()).unwrap();
+        thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                // the whole request is read, unread bytes would reset the connection
+                let mut req_buffer = [0_u8; 1024];
+                let mut bytes_read = 0;
+                while !req_buffer[..bytes_read].ends_with(TARGET_URL) {
+                    bytes_read += stream.read(&mut req_buffer[bytes_read..]).unwrap();
+                }
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        })
+    }
+
+    /// Send `REQUEST` through the proxy, returns the raw response written to the client
+    fn proxy_request(cache: &Arc<HTTPCache>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(REQUEST).unwrap();
+        let (proxy_stream, _) = listener.accept().unwrap();
+
+        handle_client_proxy_connection(proxy_stream, cache).unwrap();
+        let mut res = String::new();
+        client.read_to_string(&mut res).unwrap();
+        res
+    }
+
+    fn stored_body(cache: &HTTPCache) -> Vec<u8> {
+        let key = cache.backend().keys().pop().expect("one entry is stored");
+        cache.peek(&key).unwrap().response.body().to_vec()
+    }
+
+    #[test]
+    fn not_modified_keeps_the_stored_body_and_etag() {
+        let _origin_lock = ORIGIN_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let origin = spawn_origin(vec![
+            "HTTP/1.1 200 OK\r\ncache-control: max-age=0\r\nlast-modified: Mon, 01 Jan 2024 00:00:00 GMT\r\ncontent-length: 2\r\n\r\nv1",
+            "HTTP/1.1 304 Not Modified\r\ncache-control: max-age=0\r\n\r\n",
+        ]);
+        let cache = Arc::new(HTTPCache::new());
+
+        proxy_request(&cache);
+        let res = proxy_request(&cache);
+        origin.join().unwrap();
+
+        assert!(res.starts_with("HTTP/1.1 200 OK"), "{res}");
+        assert!(res.ends_with("\r\n\r\nv1"), "{res}");
+        let etag = generate_etag(b"v1");
+        assert!(
+            res.contains(&format!("etag: {}", etag.to_str().unwrap())),
+            "{res}"
+        );
+    }
+
+    #[test]
+    fn stale_while_revalidate_serves_stale_then_refreshes() {
+        let _origin_lock = ORIGIN_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let origin = spawn_origin(vec![
+            "HTTP/1.1 200 OK\r\ncache-control: max-age=0, stale-while-revalidate=60\r\ncontent-length: 2\r\n\r\nv1",
+            "HTTP/1.1 200 OK\r\ncache-control: max-age=0, stale-while-revalidate=60\r\ncontent-length: 2\r\n\r\nv2",
+        ]);
+        let cache = Arc::new(HTTPCache::new());
+
+        proxy_request(&cache);
+        let res = proxy_request(&cache);
+        assert!(res.ends_with("\r\n\r\nv1"), "{res}");
+        assert!(res.contains(WARNING_STALE), "{res}");
+
+        // the background revalidation replaces the entry
+        origin.join().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while stored_body(&cache) != b"v2" {
+            assert!(Instant::now() < deadline, "entry was not revalidated");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn stale_if_error_serves_stale_when_origin_fails() {
+        let _origin_lock = ORIGIN_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let origin = spawn_origin(vec![
+            "HTTP/1.1 200 OK\r\ncache-control: max-age=0, stale-if-error=60\r\ncontent-length: 2\r\n\r\nv1",
+            "HTTP/1.1 500 Internal Server Error\r\ncontent-length: 0\r\n\r\n",
+        ]);
+        let cache = Arc::new(HTTPCache::new());
+
+        proxy_request(&cache);
+        let res = proxy_request(&cache);
+        origin.join().unwrap();
+
+        assert!(res.starts_with("HTTP/1.1 200 OK"), "{res}");
+        assert!(res.ends_with("\r\n\r\nv1"), "{res}");
+        assert!(res.contains(WARNING_REVALIDATION_FAILED), "{res}");
+        assert!(res.contains("fwd=stale"), "{res}");
+        // the failed revalidation did not replace the entry
+        assert_eq!(stored_body(&cache), b"v1");
+    }
+}
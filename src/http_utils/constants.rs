@@ -7,6 +7,11 @@ pub const SIZE_MAX_BODY: usize = 10000000;
 /// Use for provisioning buffers
 pub const SIZE_MAX_HEADERS: usize = 2_usize.pow(10) * 8; // 1024 * 8 = 8192
 pub const AMT_MAX_HEADERS: usize = 64;
+/// Extension method for removing entries from the cache, see `PurgeScope`
+pub const PURGE_METHOD: &str = "PURGE";
+/// Largest `delta-seconds` value (`max-age`, `age`, ...), bigger ones are clamped to it (RFC 9111 section 1.2.2)
+pub const DELTA_SECONDS_MAX: i64 = 2_147_483_648;
+
 // main
 pub const ORIGIN_PORT: u16 = 8080;
 pub const ORIGIN_ADDR: &str = "127.0.0.1";
@@ -17,6 +22,72 @@ pub const PROXY_ADDR: &str = "127.0.0.1";
 ///////////////////////////////////////////////
 
 // cache-utils > cache
-pub const CACHE_MAX_ENTRIES: usize = 1000;
+/// Byte budget for cached headers and bodies, entries are evicted past this
+pub const CACHE_MAX_BYTES: usize = 64 * 1024 * 1024;
+/// Responses bigger than this are served but not cached
+pub const CACHE_MAX_OBJECT_BYTES: usize = 1024 * 1024;
+/// Amount of independently locked shards the cache is split into
+pub const CACHE_SHARDS: usize = 16;
+/// Default freshness lifetime, used when the upstream response has no explicit expiry.
 /// needs to be int for date math
 pub const CACHE_TTL_SEC: i64 = 30;
+/// Heuristic freshness: percentage of the time since `last-modified`
+pub const CACHE_HEURISTIC_PERCENT: i64 = 10;
+/// Heuristic freshness is capped to 1 day
+pub const CACHE_HEURISTIC_MAX_SEC: i64 = 60 * 60 * 24;
+// cache-utils > eviction
+/// Counters per row of the W-TinyLFU frequency sketch, halved every 10x this many accesses
+pub const EVICTION_SKETCH_WIDTH: usize = 4096;
+/// Frequency sketch counters saturate at this
+pub const EVICTION_SKETCH_MAX_COUNT: u8 = 15;
+/// Share of a shard's keys in the W-TinyLFU admission window
+pub const EVICTION_TINYLFU_WINDOW_PERCENT: usize = 1;
+/// Share of the W-TinyLFU main segment kept for keys accessed more than once
+pub const EVICTION_TINYLFU_PROTECTED_PERCENT: usize = 80;
+// cache-utils > negative caching
+/// Default freshness lifetime of cached error responses
+pub const CACHE_NEGATIVE_TTL_SEC: i64 = 5;
+/// Error statuses cached by default
+pub const CACHE_NEGATIVE_STATUSES: [u16; 5] = [404, 410, 502, 503, 504];
+// cache-utils > disk
+/// Default byte budget for the disk tier
+pub const CACHE_DISK_MAX_BYTES: u64 = 1024 * 1024 * 1024;
+/// Name of the disk tier's index, inside the cache directory
+pub const CACHE_DISK_INDEX_FILE: &str = "index.json";
+// cache-utils > redis
+/// Default address of the Redis-protocol server for the redis backend
+pub const CACHE_REDIS_ADDR: &str = "127.0.0.1:6379";
+/// Prefix of the proxy's keys on the Redis-protocol server
+pub const CACHE_REDIS_KEY_PREFIX: &str = "tcp_proxy:";
+/// Read and write timeout on the Redis-protocol server connection
+pub const CACHE_REDIS_TIMEOUT_MS: u64 = 1000;
+/// Max nesting of RESP arrays, deeper replies are rejected instead of recursing further
+pub const CACHE_REDIS_MAX_DEPTH: usize = 8;
+// cache-utils > compression
+/// Bodies smaller than this are stored uncompressed
+pub const COMPRESSION_MIN_BYTES: usize = 256;
+/// gzip level of stored bodies (0-9)
+pub const COMPRESSION_GZIP_LEVEL: u32 = 6;
+/// zstd level of stored bodies (1-22)
+pub const COMPRESSION_ZSTD_LEVEL: i32 = 3;
+// cache-utils > warm
+/// Default amount of concurrent origin requests when warming the cache
+pub const CACHE_WARM_CONCURRENCY: usize = 4;
+// cache-utils > ttl
+/// Default interval between background sweeps for expired entries
+pub const CACHE_SWEEP_INTERVAL_SEC: u64 = 10;
+/// Expired entries with an `etag` or `last-modified` are kept this long, so they can be revalidated
+pub const CACHE_STALE_RETENTION_SEC: i64 = 60 * 5;
+/// Stale entries are never served past this, unless configured per route
+pub const CACHE_MAX_STALENESS_SEC: i64 = 60 * 60;
+/// Default max expiry jitter, as share of each entry's freshness lifetime (disabled)
+pub const CACHE_EXPIRY_JITTER_PERCENT: u64 = 0;
+/// Entries served at least this many times are refreshed in the background before they expire
+pub const CACHE_REFRESH_AHEAD_MIN_HITS: u64 = 3;
+/// Hot entries are refreshed once their remaining freshness falls below this share of their lifetime
+pub const CACHE_REFRESH_AHEAD_PERCENT: u64 = 10;
+/// Name of the proxy in the `cache-status` header sent to clients (RFC 9211)
+pub const CACHE_STATUS_NAME: &str = "tcp-proxy";
+/// `warning` sent with stale responses (RFC 7234 section 5.5)
+pub const WARNING_STALE: &str = "110 - \"Response is Stale\"";
+pub const WARNING_REVALIDATION_FAILED: &str = "111 - \"Revalidation Failed\"";
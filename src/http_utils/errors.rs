@@ -19,6 +19,10 @@ pub enum RequestError {
     ConnectionError(failure::Error),
     /// Cannot handle certain method
     InvalidMethod,
+    /// `PURGE` without the configured purge token, or with purging disabled
+    Unauthorized,
+    /// The request body (target url) is not a valid absolute url
+    InvalidTargetUrl,
     MiscError(ResponseError),
 }
 
@@ -56,6 +60,20 @@ pub enum ConnectionError {
     ClientProxyStream,
 }
 
+#[derive(Debug)]
+pub enum StorageError {
+    /// File does not start with the expected magic bytes
+    InvalidMagic,
+    /// File was written by an incompatible version of the proxy
+    UnsupportedVersion(u16),
+    /// Stored checksum does not match the contents (partial write, disk corruption)
+    ChecksumMismatch,
+    /// Contents could not be decoded into a cache entry
+    InvalidEntry,
+    /// Cache backend replied with an error
+    BackendError(String),
+}
+
 pub fn fmt_error<T>(e: T, msg: &str) -> failure::Error
 where
     T: std::fmt::Debug,
@@ -1,4 +1,6 @@
 // imports
+use chrono::{DateTime, Utc};
+use http::HeaderValue;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
 // local
@@ -18,4 +20,24 @@ pub fn new_endpoint_str(addr: &str, port: u16) -> String {
     endpoint.to_string()
 }
 
+/// Parse an HTTP-date header value (i.e. `date`, `expires`, `last-modified`)
+///
+/// Returns None for invalid dates
+pub fn parse_http_date(header_value: &HeaderValue) -> Option<DateTime<Utc>> {
+    let header_str = header_value.to_str().ok()?;
+    let timestamp = DateTime::parse_from_rfc2822(header_str.trim()).ok()?;
+
+    Some(timestamp.with_timezone(&Utc))
+}
+
+/// 64-bit FNV-1a hash over a sequence of byte chunks
+pub fn fnv1a_hash(chunks: &[&[u8]]) -> u64 {
+    chunks
+        .iter()
+        .flat_map(|chunk| chunk.iter())
+        .fold(0xcbf29ce484222325_u64, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+        })
+}
+
 pub type Result<T> = std::result::Result<T, failure::Error>;
@@ -1,3 +1,6 @@
+pub mod cache_control;
+pub mod cache_status;
+pub mod conditional;
 pub mod connection;
 pub mod constants;
 pub mod errors;
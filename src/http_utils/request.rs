@@ -8,6 +8,7 @@ pub use super::{
     constants::*,
     errors::{fmt_error, RequestError, Result},
 };
+use crate::cache_utils::purge::is_supported_method;
 
 /// This function forwards the incoming request to the `origin`.
 ///
@@ -44,7 +45,7 @@ pub fn get_parsed_request(stream: &mut TcpStream) -> Result<Request<Vec<u8>>> {
         // TODO: propagate error to client http response
         let new_bytes = stream
             .read(&mut in_buffer[bytes_read..])
-            .or_else(|err| Err(fmt_error(err, "RequestError::ConnectionError")))?;
+            .map_err(|err| fmt_error(err, "RequestError::ConnectionError"))?;
 
         // handle incomplete request
         if new_bytes == 0 {
@@ -57,13 +58,13 @@ pub fn get_parsed_request(stream: &mut TcpStream) -> Result<Request<Vec<u8>>> {
         let mut req = httparse::Request::new(&mut headers);
 
         if let Ok(parsed) = req.parse(&in_buffer) {
-            // 1.a) check request, proceed if GET requets
+            // 1.a) check request, proceed if GET request (or PURGE, for invalidation)
             // TODO: handle error if no origin
             // TODO: propagate error to client http response
 
-            if req.method.unwrap() != http::Method::GET {
+            if !is_supported_method(req.method.unwrap()) {
                 return Err(failure::err_msg(format!(
-                    "Error::RequestMethod- please use GET.  Submitted: {}",
+                    "Error::RequestMethod- please use GET or PURGE.  Submitted: {}",
                     req.method.unwrap()
                 )));
             }
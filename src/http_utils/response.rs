@@ -3,7 +3,7 @@ use failure;
 use http::Response;
 use httparse;
 use serde::{Deserialize, Serialize};
-use std::{io::Read, net::TcpStream, sync::Mutex};
+use std::{io::Read, net::TcpStream};
 // local
 pub use super::{
     connection::{check_body_len, write_to_stream},
@@ -45,7 +45,7 @@ fn parse_res_from_origin(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>
     // TODO: propagate error to client http response
     let res_status = res_init
         .parse(buffer)
-        .or_else(|err| Err(fmt_error(ResponseError::MalformedResponse(err), "")))?;
+        .map_err(|err| fmt_error(ResponseError::MalformedResponse(err), ""))?;
 
     // if this is a complete request, build and return response
     // TODO: propagate error to client http response
@@ -81,11 +81,11 @@ pub fn read_res_from_origin(proxy_origin_stream: &mut TcpStream) -> Result<Respo
         // TODO: propagate error to client http response
         let new_bytes = proxy_origin_stream
             .read(&mut res_buffer[bytes_read..])
-            .or_else(|err| {
-                Err(fmt_error(
+            .map_err(|err| {
+                fmt_error(
                     ResponseError::ConnectionError(err),
                     "Error reading new byes:",
-                ))
+                )
             })?;
 
         // handle incomplete response
@@ -99,7 +99,7 @@ pub fn read_res_from_origin(proxy_origin_stream: &mut TcpStream) -> Result<Respo
     // check for valid response
     // TODO: propagate error to client http response
     let parsed_res_option = parse_res_from_origin(&res_buffer[..bytes_read])?;
-    if let None = parsed_res_option {
+    if parsed_res_option.is_none() {
         return Err(failure::err_msg("Incomplete response - returned none"));
     }
 
@@ -110,43 +110,54 @@ pub fn read_res_from_origin(proxy_origin_stream: &mut TcpStream) -> Result<Respo
         .body_mut()
         .extend_from_slice(&res_buffer[headers_len..bytes_read]);
 
-    return Ok(parsed_res);
+    Ok(parsed_res)
+}
+
+/// Copy a response
+///
+/// `http::Response` is not `Clone` (because of its extensions), so only status, version, headers and body are copied
+pub fn clone_response<T: Clone>(res: &Response<T>) -> Response<T> {
+    let mut new_res = Response::new(res.body().clone());
+    *new_res.status_mut() = res.status();
+    *new_res.version_mut() = res.version();
+    *new_res.headers_mut() = res.headers().clone();
+
+    new_res
 }
 
 /// Build the response object to send to the client
-/// Response value is mutex because it could be coming from the cache
-/// Lock drop happens when scope closes
-/// TODO: consider- unwrapping mutex before the fxn call
-pub fn write_response_to_client<'b>(
+/// Responses coming from the cache share their body, no lock is held while writing
+pub fn write_response_to_client<T: AsRef<[u8]>>(
     stream: &mut TcpStream,
-    res: &Mutex<Response<Vec<u8>>>,
+    res: &Response<T>,
 ) -> Result<()> {
-    let res = res.lock().expect("Poisoned mutex: writing to client");
     let status_str = format!(
         "{:?} {} {}",
         res.version(),
         res.status().as_str(),
         res.status().canonical_reason().unwrap_or("")
     );
-    write_to_stream(stream, status_str, res.headers(), res.body())?;
+    write_to_stream(stream, status_str, res.headers(), res.body().as_ref())?;
 
     Ok(())
 }
 
-/// PLACEHOLDER - Does not do anything
+/// Function takes the returned error, builds and sends the response
 ///
-/// Function takes the returned error, initiates builds and sends the response
+/// The error message is sent as the response body
 pub fn write_error_res(err: &failure::Error, stream: &mut TcpStream, err_status: u16) {
     ////////////////////////////////////////////////////
     // create the response (below)
-    let res = Response::new("");
-    let builder = Response::builder()
+    let body = err.to_string().into_bytes();
+    let res = Response::builder()
         .status(err_status)
-        .version(http::Version::HTTP_11);
+        .version(http::Version::HTTP_11)
+        .header("content-length", body.len())
+        .body(body)
+        .unwrap_or_else(|_| Response::new(Vec::new()));
     // create the response (above)
     ////////////////////////////////////////////////////
 
-    let status_str = String::from("");
     let status_str = format!(
         "{:?} {} {}",
         res.version(),
@@ -154,5 +165,7 @@ pub fn write_error_res(err: &failure::Error, stream: &mut TcpStream, err_status:
         res.status().canonical_reason().unwrap_or("")
     );
 
-    // write_to_stream(stream, status_str, header_map, http_body);
+    if let Err(e) = write_to_stream(stream, status_str, res.headers(), res.body()) {
+        eprintln!("Error writing error response: {e}");
+    }
 }